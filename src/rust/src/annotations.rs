@@ -0,0 +1,67 @@
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Value of a user annotation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Value {
+    Number(f64),
+    Text(String),
+}
+
+/// User annotations such as the sample ID, subject, group, timepoint or
+/// batch, keyed by name.
+pub(crate) type Annotations = BTreeMap<String, Value>;
+
+/// Reads annotations from a named list of single strings, finite numbers or
+/// factors. Factors are stored as the label of their level.
+pub(crate) fn from_list(annotations: &List) -> Result<Annotations> {
+    annotations
+        .iter()
+        .map(|(name, value)| {
+            if name.is_empty() {
+                return Err(Error::from("annotations must be named"));
+            }
+            // Integer NA is the smallest i32.
+            let integer = value.as_integer().filter(|integer| *integer != i32::MIN);
+            let value = if value.len() != 1 {
+                None
+            } else if value.is_factor() {
+                integer
+                    .and_then(|code| usize::try_from(code).ok())
+                    .zip(value.levels())
+                    .and_then(|(code, mut levels)| levels.nth(code.checked_sub(1)?))
+                    .map(|label| Value::Text(label.to_string()))
+            } else if let Some(text) = value.as_str() {
+                Some(Value::Text(text.to_string()))
+            } else if let Some(number) = value.as_real() {
+                number.is_finite().then_some(Value::Number(number))
+            } else {
+                integer.map(|integer| Value::Number(integer as f64))
+            };
+            value.map(|value| (name.to_string(), value)).ok_or_else(|| {
+                Error::from(format!(
+                    "annotation {:?} must be a single string, finite number or factor level",
+                    name
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Converts annotations into a named list.
+pub(crate) fn to_list(annotations: &Annotations) -> Result<List> {
+    let (names, values): (Vec<&str>, Vec<Robj>) = annotations
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::Number(number) => number.into(),
+                Value::Text(text) => text.into(),
+            };
+            (name.as_str(), value)
+        })
+        .unzip();
+
+    List::from_names_and_values(names, values)
+}
@@ -0,0 +1,239 @@
+use crate::util;
+use extendr_api::prelude::*;
+use metabodecon::spectrum;
+
+/// Strategy used to place the bin edges.
+#[derive(Clone, Debug)]
+pub(crate) enum Binning {
+    /// Bins of equal width, with edges on multiples of the width.
+    Uniform { width: f64 },
+    /// Bins given explicitly as `(start, end)` pairs.
+    Table { bins: Vec<(f64, f64)> },
+    /// Uniform bins whose inner edges are moved to the lowest point of the
+    /// mean spectrum within `slackness * width`, so that peaks are not cut.
+    Adaptive { width: f64, slackness: f64 },
+}
+
+/// Bin table and the integrated intensities of each spectrum in each bin.
+#[derive(Clone, Debug)]
+pub(crate) struct Buckets {
+    pub(crate) bins: Vec<(f64, f64)>,
+    pub(crate) values: Vec<Vec<f64>>,
+}
+
+impl Buckets {
+    pub(crate) fn centres(&self) -> Vec<f64> {
+        self.bins
+            .iter()
+            .map(|(start, end)| 0.5 * (start + end))
+            .collect()
+    }
+
+    pub(crate) fn to_list(&self) -> Result<List> {
+        let start = self.bins.iter().map(|bin| bin.0).collect::<Vec<f64>>();
+        let end = self.bins.iter().map(|bin| bin.1).collect::<Vec<f64>>();
+        let values: Vec<Robj> = vec![
+            util::matrix(&self.values, self.bins.len())?,
+            start.into(),
+            end.into(),
+            self.centres().into(),
+        ];
+
+        List::from_names_and_values(["matrix", "start", "end", "centre"], values)
+    }
+}
+
+fn is_ignored(x: f64, ignore_regions: &[(f64, f64)]) -> bool {
+    ignore_regions
+        .iter()
+        .any(|(start, end)| x >= *start && x <= *end)
+}
+
+/// Splits `range` into the parts that are not covered by the (merged) ignore
+/// regions.
+fn segments(range: (f64, f64), ignore_regions: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    let mut segments = Vec::new();
+    let mut start = range.0;
+    for (ignore_start, ignore_end) in ignore_regions {
+        if *ignore_end <= start || *ignore_start >= range.1 {
+            continue;
+        }
+        if *ignore_start > start {
+            segments.push((start, *ignore_start));
+        }
+        start = start.max(*ignore_end);
+    }
+    if start < range.1 {
+        segments.push((start, range.1));
+    }
+
+    segments
+}
+
+fn uniform_edges(segment: (f64, f64), width: f64) -> Vec<f64> {
+    let tolerance = width * 1e-9;
+    let mut edges = vec![segment.0];
+    let mut k = (segment.0 / width).floor() + 1.0;
+    while k * width < segment.1 - tolerance {
+        if k * width - segment.0 > tolerance {
+            edges.push(k * width);
+        }
+        k += 1.0;
+    }
+    edges.push(segment.1);
+
+    edges
+}
+
+fn edges_to_bins(edges: &[f64]) -> impl Iterator<Item = (f64, f64)> + '_ {
+    edges.windows(2).map(|edge| (edge[0], edge[1]))
+}
+
+fn adaptive_edges(
+    segment: (f64, f64),
+    width: f64,
+    slackness: f64,
+    chemical_shifts: &[f64],
+    profile: &[f64],
+) -> Vec<f64> {
+    let mut edges = uniform_edges(segment, width);
+    let slack = slackness * width;
+    for i in 1..edges.len().saturating_sub(1) {
+        let lower = (edges[i] - slack).max(edges[i - 1]);
+        let upper = (edges[i] + slack).min(edges[i + 1]);
+        let minimum = chemical_shifts
+            .iter()
+            .zip(profile.iter())
+            .filter(|(x, _)| **x > lower && **x < upper)
+            .min_by(|a, b| a.1.total_cmp(b.1));
+        if let Some((x, _)) = minimum {
+            edges[i] = *x;
+        }
+    }
+
+    edges
+}
+
+/// Integrates the intensities within each bin with the rectangle rule,
+/// skipping data points inside the ignore regions.
+fn integrate(
+    spectrum: &spectrum::Spectrum,
+    bins: &[(f64, f64)],
+    ignore_regions: &[(f64, f64)],
+) -> Vec<f64> {
+    let chemical_shifts = spectrum.chemical_shifts();
+    let intensities = spectrum.intensities();
+    let step = match chemical_shifts.len() {
+        0 | 1 => 1.0,
+        len => (chemical_shifts[len - 1] - chemical_shifts[0]).abs() / (len - 1) as f64,
+    };
+    let mut values = vec![0.0; bins.len()];
+    chemical_shifts
+        .iter()
+        .zip(intensities.iter())
+        .filter(|(x, _)| !is_ignored(**x, ignore_regions))
+        .for_each(|(x, y)| {
+            let index = bins.partition_point(|(_, end)| *end <= *x);
+            if index < bins.len() && bins[index].0 <= *x {
+                values[index] += y * step;
+            }
+        });
+
+    values
+}
+
+/// Bins the given spectra with a common set of bins. Unless given as a
+/// table, the bins cover the chemical shift range that lies within the signal
+/// boundaries of every spectrum.
+pub(crate) fn bin_spectra<S: AsRef<spectrum::Spectrum>>(
+    spectra: &[S],
+    binning: &Binning,
+    ignore_regions: &[(f64, f64)],
+) -> Result<Buckets> {
+    if spectra.is_empty() {
+        return Err(Error::from("at least one spectrum is required for binning"));
+    }
+    let ignore_regions = util::merge_regions(ignore_regions);
+    let range = spectra
+        .iter()
+        .map(|spectrum| {
+            let (start, end) = spectrum.as_ref().signal_boundaries();
+            (start.min(end), start.max(end))
+        })
+        .fold((f64::NEG_INFINITY, f64::INFINITY), |acc, (start, end)| {
+            (acc.0.max(start), acc.1.min(end))
+        });
+    let bins = match binning {
+        Binning::Uniform { width } => {
+            if !(width.is_finite() && *width > 0.0) {
+                return Err(Error::from("bin width must be a positive number"));
+            }
+            if range.0 >= range.1 {
+                return Err(Error::from("signal regions of the spectra do not overlap"));
+            }
+            segments(range, &ignore_regions)
+                .into_iter()
+                .flat_map(|segment| {
+                    edges_to_bins(&uniform_edges(segment, *width)).collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        }
+        Binning::Table { bins } => {
+            let mut bins = bins
+                .iter()
+                .map(|(a, b)| (a.min(*b), a.max(*b)))
+                .filter(|(start, end)| {
+                    start < end
+                        && !ignore_regions.iter().any(|(ignore_start, ignore_end)| {
+                            ignore_start <= start && ignore_end >= end
+                        })
+                })
+                .collect::<Vec<_>>();
+            bins.sort_by(|a, b| a.0.total_cmp(&b.0));
+            if bins.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+                return Err(Error::from("bins must not overlap"));
+            }
+            bins
+        }
+        Binning::Adaptive { width, slackness } => {
+            if !(width.is_finite() && *width > 0.0) {
+                return Err(Error::from("bin width must be a positive number"));
+            }
+            if !(*slackness > 0.0 && *slackness <= 0.5) {
+                return Err(Error::from("slackness must be in (0, 0.5]"));
+            }
+            if range.0 >= range.1 {
+                return Err(Error::from("signal regions of the spectra do not overlap"));
+            }
+            let chemical_shifts = spectra[0].as_ref().chemical_shifts();
+            if spectra.iter().any(|spectrum| {
+                !util::shares_axis(spectrum.as_ref().chemical_shifts(), chemical_shifts)
+            }) {
+                return Err(Error::from(
+                    "adaptive binning requires spectra with a common chemical shift axis",
+                ));
+            }
+            let mut profile = vec![0.0; chemical_shifts.len()];
+            spectra.iter().for_each(|spectrum| {
+                profile
+                    .iter_mut()
+                    .zip(spectrum.as_ref().intensities().iter())
+                    .for_each(|(p, y)| *p += y / spectra.len() as f64)
+            });
+            segments(range, &ignore_regions)
+                .into_iter()
+                .flat_map(|segment| {
+                    let edges =
+                        adaptive_edges(segment, *width, *slackness, chemical_shifts, &profile);
+                    edges_to_bins(&edges).collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        }
+    };
+    let values = spectra
+        .iter()
+        .map(|spectrum| integrate(spectrum.as_ref(), &bins, &ignore_regions))
+        .collect();
+
+    Ok(Buckets { bins, values })
+}
@@ -0,0 +1,69 @@
+use crate::util;
+use extendr_api::prelude::*;
+
+/// Fraction of the data points at either end of the spectrum assumed to be
+/// free of signals when estimating the noise.
+const EDGE_FRACTION: f64 = 0.05;
+
+/// Fraction of the data points per window when scanning for signals.
+const WINDOW_FRACTION: f64 = 0.005;
+
+/// Number of noise standard deviations a window must exceed to count as
+/// signal.
+const THRESHOLD: f64 = 6.0;
+
+/// Determines the signal boundaries of a spectrum from its local noise
+/// statistics.
+///
+/// The noise level is estimated robustly from the first differences of the
+/// outermost data points and the baseline is interpolated between the medians
+/// of both edges. Scanning inwards from either end, the first window that
+/// deviates from the baseline by more than [`THRESHOLD`] noise standard
+/// deviations marks the end of the noise. The boundaries are placed one window
+/// further out and returned as `(lower, upper)` in ppm.
+pub(crate) fn detect(chemical_shifts: &[f64], intensities: &[f64]) -> Result<(f64, f64)> {
+    let len = chemical_shifts.len();
+    let edge = ((len as f64 * EDGE_FRACTION) as usize).max(8);
+    let window = ((len as f64 * WINDOW_FRACTION) as usize).max(4);
+    if len < 2 * edge + 2 * window {
+        return Err(Error::from(
+            "too few data points to detect the signal boundaries",
+        ));
+    }
+    let left = &intensities[..edge];
+    let right = &intensities[len - edge..];
+    let mut differences = left
+        .windows(2)
+        .chain(right.windows(2))
+        .map(|pair| (pair[1] - pair[0]).abs())
+        .collect::<Vec<f64>>();
+    let sd = 1.4826 * util::median(&mut differences).unwrap_or_default() / std::f64::consts::SQRT_2;
+    let left_baseline = util::median(&mut left.to_vec()).unwrap_or_default();
+    let right_baseline = util::median(&mut right.to_vec()).unwrap_or_default();
+    let baseline =
+        |index: usize| left_baseline + (right_baseline - left_baseline) * index as f64 / len as f64;
+    let is_signal = |start: usize| {
+        (start..start + window).any(|index| {
+            (intensities[index] - baseline(index)).abs() > THRESHOLD * sd.max(f64::EPSILON)
+        })
+    };
+
+    let first = (0..=len - window)
+        .step_by(window)
+        .find(|start| is_signal(*start))
+        .map(|start| start.saturating_sub(window))
+        .ok_or_else(|| Error::from("no signal found in the spectrum"))?;
+    let last = (0..=len - window)
+        .rev()
+        .step_by(window)
+        .find(|start| is_signal(*start))
+        .map(|start| (start + 2 * window).min(len - 1))
+        .ok_or_else(|| Error::from("no signal found in the spectrum"))?;
+    if first >= last {
+        return Err(Error::from("no signal found in the spectrum"));
+    }
+    let (first, last) = (first.max(1), last.min(len - 2));
+    let (a, b) = (chemical_shifts[first], chemical_shifts[last]);
+
+    Ok((a.min(b), a.max(b)))
+}
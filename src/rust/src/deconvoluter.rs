@@ -1,37 +1,455 @@
 use crate::deconvolution::Deconvolution;
+use crate::fitting;
+use crate::optimization;
+use crate::presets;
+use crate::profiles;
+use crate::provenance;
 use crate::spectrum::Spectrum;
+use crate::targets;
+use crate::util;
 use extendr_api::prelude::*;
 use metabodecon::deconvolution;
 use std::collections::HashMap;
+use rayon::prelude::*;
 use rayon::{ThreadPool, ThreadPoolBuilder};
 use std::sync::Arc;
 
 #[derive(Clone, Debug, Default)]
 pub(crate) struct Deconvoluter {
     inner: deconvolution::Deconvoluter,
-    threads: Option<Arc<ThreadPool>>
+    threads: Option<Arc<ThreadPool>>,
+    /// Ignore region presets, scaled to the spectrometer frequency of each
+    /// spectrum when it is deconvoluted.
+    presets: Vec<presets::Preset>,
+    /// Name of the profile the settings were initialised from, cleared when
+    /// any of the settings the profile defines is changed.
+    profile: Option<String>,
+    /// Bounds and multiplet patterns enforced by refining the fit.
+    bounds: fitting::Bounds,
 }
 
-/// @eval make_r_docs("Deconvoluter")
-#[extendr]
 impl Deconvoluter {
-    pub(crate) fn new() -> Self {
-        Self::default()
+    /// Ignore regions of the presets for the nucleus of the spectrum at its
+    /// spectrometer frequency, followed by the ignore regions of the spectrum
+    /// itself.
+    fn spectrum_ignore_regions(&self, spectrum: &Spectrum) -> Vec<(f64, f64)> {
+        let frequency = spectrum.as_ref().frequency();
+        let nucleus = presets::normalise_nucleus(&spectrum.as_ref().nucleus().to_string());
+
+        self.presets
+            .iter()
+            .filter(|preset| preset.nucleus == nucleus)
+            .flat_map(|preset| preset.at_frequency(frequency))
+            .chain(spectrum.sample_ignore_regions().unwrap_or_default().iter().copied())
+            .collect()
     }
 
-    pub(crate) fn smoothing_settings(&self) -> Result<List> {
+    /// Sets the smoothing, selection and fitting settings of a candidate.
+    fn apply_candidate(&mut self, candidate: &optimization::Candidate) -> Result<()> {
+        self.inner
+            .set_smoothing_settings(deconvolution::SmoothingSettings::MovingAverage {
+                iterations: candidate.smoothing_iterations,
+                window_size: candidate.smoothing_window_size,
+            })
+            .map_err(|error| Error::from(error.to_string()))?;
+        self.inner
+            .set_selection_settings(deconvolution::SelectionSettings::NoiseScoreFilter {
+                scoring_method: deconvolution::ScoringMethod::MinimumSum,
+                threshold: candidate.noise_threshold,
+            })
+            .map_err(|error| Error::from(error.to_string()))?;
+        self.inner
+            .set_fitting_settings(deconvolution::FittingSettings::Analytical {
+                iterations: candidate.fitting_iterations,
+            })
+            .map_err(|error| Error::from(error.to_string()))?;
+        self.profile = None;
+
+        Ok(())
+    }
+
+    /// Deconvolutes the reference with the settings of a candidate and scores
+    /// the result.
+    fn evaluate(
+        &self,
+        reference: &Spectrum,
+        candidate: optimization::Candidate,
+        objective: optimization::Objective,
+    ) -> optimization::Evaluation {
+        let mut deconvoluter = self.clone();
+        let outcome = deconvoluter
+            .apply_candidate(&candidate)
+            .map_err(|error| error.to_string())
+            .and_then(|_| deconvoluter.deconvolute(reference, false));
+        match outcome {
+            Ok(deconvolution) => {
+                let peaks = deconvolution.lorentzians().len();
+                let points = optimization::fitted_points(reference);
+                optimization::Evaluation {
+                    candidate,
+                    mse: Some(deconvolution.mse()),
+                    mean_peaks: Some(peaks as f64),
+                    score: Some(objective.score(deconvolution.mse(), peaks, points)),
+                    error: None,
+                }
+            }
+            Err(error) => optimization::Evaluation::failed(candidate, error),
+        }
+    }
+
+    /// Evaluates every candidate of a search on the reference spectra,
+    /// optionally in parallel, and applies the best settings if requested.
+    /// If the search has folds, the selection is cross-validated as well.
+    fn search(
+        &mut self,
+        references: &[Spectrum],
+        search: &optimization::Search,
+        apply: bool,
+        parallel: bool,
+    ) -> Result<List> {
+        let deconvoluter = &*self;
+        let evaluate_candidate = |candidate: &optimization::Candidate| {
+            if parallel {
+                references
+                    .par_iter()
+                    .map(|reference| deconvoluter.evaluate(reference, *candidate, search.objective))
+                    .collect::<Vec<_>>()
+            } else {
+                references
+                    .iter()
+                    .map(|reference| deconvoluter.evaluate(reference, *candidate, search.objective))
+                    .collect::<Vec<_>>()
+            }
+        };
+        let evaluate_all = || {
+            if parallel {
+                search
+                    .candidates
+                    .par_iter()
+                    .map(evaluate_candidate)
+                    .collect::<Vec<Vec<optimization::Evaluation>>>()
+            } else {
+                search
+                    .candidates
+                    .iter()
+                    .map(evaluate_candidate)
+                    .collect::<Vec<Vec<optimization::Evaluation>>>()
+            }
+        };
+        let per_spectrum = match (&self.threads, parallel) {
+            (Some(threads), true) => threads.install(evaluate_all),
+            _ => evaluate_all(),
+        };
+        let evaluations = search
+            .candidates
+            .iter()
+            .zip(per_spectrum.iter())
+            .map(|(candidate, evaluations)| search.combine(*candidate, evaluations, |_| true))
+            .collect::<Vec<optimization::Evaluation>>();
+        let best = optimization::Evaluation::best(&evaluations);
+        if let (true, Some(best)) = (apply, best) {
+            self.apply_candidate(&best.candidate)?;
+        }
+        let mut result = HashMap::<&str, Robj>::new();
+        result.insert(
+            "best",
+            match best {
+                Some(best) => best.to_list()?.into(),
+                None => ().into(),
+            },
+        );
+        result.insert("table", optimization::Evaluation::table(&evaluations)?);
+        if let Some(folds) = search.cross_validate(&per_spectrum) {
+            result.insert("cross_validation", optimization::Fold::table(&folds)?);
+        }
+
+        List::from_hashmap(result)
+    }
+
+    /// Creates a deconvoluter with the settings of a profile.
+    fn with_profile(profile: &profiles::Profile) -> Result<Self> {
+        let mut deconvoluter = Self::default();
+        deconvoluter.apply_candidate(&optimization::Candidate {
+            smoothing_iterations: profile.smoothing_iterations,
+            smoothing_window_size: profile.smoothing_window_size,
+            noise_threshold: profile.noise_threshold,
+            fitting_iterations: profile.fitting_iterations,
+        })?;
+        for preset in &profile.ignore_presets {
+            deconvoluter
+                .presets
+                .push(presets::lookup(preset, &profile.nucleus)?);
+        }
+        deconvoluter.profile = Some(profile.name.clone());
+
+        Ok(deconvoluter)
+    }
+
+    /// Deconvolutes a spectrum and enforces the parameter bounds and
+    /// multiplet patterns.
+    fn deconvolute(
+        &self,
+        spectrum: &Spectrum,
+        parallel: bool,
+    ) -> std::result::Result<deconvolution::Deconvolution, String> {
+        let deconvolution = self.deconvolute_unconstrained(spectrum, parallel)?;
+
+        self.enforce_bounds(spectrum, deconvolution)
+    }
+
+    /// Refines a deconvolution to enforce the parameter bounds and multiplet
+    /// patterns. If only the sign of the scale factors is constrained, just
+    /// the peaks with a negative scale factor are refit.
+    fn enforce_bounds(
+        &self,
+        spectrum: &Spectrum,
+        deconvolution: deconvolution::Deconvolution,
+    ) -> std::result::Result<deconvolution::Deconvolution, String> {
+        let peaks = deconvolution.lorentzians().to_vec();
+        let constraints = match self.bounds.is_active() {
+            true => Vec::new(),
+            false => match self.bounds.violations(&peaks) {
+                Some(constraints) => constraints,
+                None => return Ok(deconvolution),
+            },
+        };
+        let mask = self.fitting_mask(spectrum);
+
+        self.refine(spectrum, peaks, &constraints, &mask)
+    }
+
+    /// Enforces the bounds on the deconvolutions of several spectra.
+    fn enforce_all(
+        &self,
+        spectra: &[Spectrum],
+        deconvolutions: Vec<deconvolution::Deconvolution>,
+    ) -> std::result::Result<Vec<deconvolution::Deconvolution>, String> {
+        deconvolutions
+            .into_iter()
+            .zip(spectra.iter())
+            .map(|(deconvolution, spectrum)| self.enforce_bounds(spectrum, deconvolution))
+            .collect()
+    }
+
+    /// Deconvolutes a spectrum with the inner deconvoluter. Ignore regions of
+    /// the presets and of the spectrum are added to those of the
+    /// deconvoluter. Spectra with
+    /// several signal regions are deconvoluted region by region, in parallel
+    /// if requested, and the results are merged.
+    fn deconvolute_unconstrained(
+        &self,
+        spectrum: &Spectrum,
+        parallel: bool,
+    ) -> std::result::Result<deconvolution::Deconvolution, String> {
+        let with_sample_regions;
+        let ignore_regions = self.spectrum_ignore_regions(spectrum);
+        let inner = if ignore_regions.is_empty() {
+            &self.inner
+        } else {
+            let mut inner = self.inner.clone();
+            for ignore_region in ignore_regions {
+                inner
+                    .add_ignore_region(ignore_region)
+                    .map_err(|error| error.to_string())?;
+            }
+            with_sample_regions = inner;
+            &with_sample_regions
+        };
+        let regions = match spectrum.disjoint_signal_regions() {
+            Some(regions) => regions,
+            None if parallel => {
+                return inner
+                    .par_deconvolute_spectrum(spectrum.as_ref())
+                    .map_err(|error| error.to_string())
+            }
+            None => {
+                return inner
+                    .deconvolute_spectrum(spectrum.as_ref())
+                    .map_err(|error| error.to_string())
+            }
+        };
+        let deconvolute_region = |region: &(f64, f64)| {
+            let mut region_spectrum = spectrum.as_ref().clone();
+            region_spectrum
+                .set_signal_boundaries(*region)
+                .map_err(|error| error.to_string())?;
+            inner
+                .deconvolute_spectrum(&region_spectrum)
+                .map_err(|error| error.to_string())
+        };
+        let deconvolutions = if parallel {
+            regions
+                .par_iter()
+                .map(deconvolute_region)
+                .collect::<std::result::Result<Vec<_>, String>>()?
+        } else {
+            regions
+                .iter()
+                .map(deconvolute_region)
+                .collect::<std::result::Result<Vec<_>, String>>()?
+        };
+
+        Ok(self.merge(spectrum, regions, deconvolutions))
+    }
+
+    /// Marks the data points used to refine a fit, i.e. those within the
+    /// signal regions and outside the ignore regions of the deconvoluter, its
+    /// presets and the spectrum.
+    fn fitting_mask(&self, spectrum: &Spectrum) -> Vec<bool> {
+        let ignore_regions = self
+            .inner
+            .ignore_regions()
+            .unwrap_or_default()
+            .iter()
+            .copied()
+            .chain(self.spectrum_ignore_regions(spectrum))
+            .collect::<Vec<(f64, f64)>>();
+
+        spectrum.signal_mask(&ignore_regions)
+    }
+
+    /// Number of sweeps used to refine peaks, taken from the iterations of the
+    /// fitting settings.
+    fn refinement_sweeps(&self) -> usize {
+        match self.inner.fitting_settings() {
+            deconvolution::FittingSettings::Analytical { iterations } => iterations,
+            _ => 10,
+        }
+    }
+
+    /// Refines peaks against the data points of a spectrum marked in `mask`,
+    /// enforcing the bounds and multiplet patterns of the deconvoluter.
+    /// `constraints` can narrow the bounds of individual peaks.
+    fn refine(
+        &self,
+        spectrum: &Spectrum,
+        mut peaks: Vec<deconvolution::Lorentzian>,
+        constraints: &[fitting::Constraint],
+        mask: &[bool],
+    ) -> std::result::Result<deconvolution::Deconvolution, String> {
+        let frequency = spectrum.as_ref().frequency();
+        let multiplets = self.bounds.multiplets(&mut peaks, frequency);
+        let constraints = peaks
+            .iter()
+            .enumerate()
+            .map(|(index, peak)| {
+                let bounds = self.bounds.constraint(peak, frequency);
+                match constraints.get(index) {
+                    Some(constraint) => constraint.and(&bounds),
+                    None => bounds,
+                }
+            })
+            .collect::<Vec<fitting::Constraint>>();
+        let chemical_shifts = spectrum.as_ref().chemical_shifts();
+        let intensities = spectrum.as_ref().intensities();
+        let peaks = fitting::refine(
+            &peaks,
+            &constraints,
+            &multiplets,
+            chemical_shifts,
+            intensities,
+            mask,
+            self.refinement_sweeps(),
+        )?;
+        let mse = fitting::mse(&peaks, chemical_shifts, intensities, mask);
+
+        Ok(deconvolution::Deconvolution::new(
+            peaks,
+            self.inner.smoothing_settings(),
+            self.inner.selection_settings(),
+            self.inner.fitting_settings(),
+            mse,
+        ))
+    }
+
+    /// Deconvolutes a spectrum starting from the peaks of an earlier
+    /// deconvolution. If `detect` is set, the peak detection is run as well
+    /// and only used to add new peaks and drop vanished ones. The peaks are
+    /// then refined against the spectrum.
+    fn deconvolute_from(
+        &self,
+        spectrum: &Spectrum,
+        initial: &deconvolution::Deconvolution,
+        detect: bool,
+    ) -> std::result::Result<deconvolution::Deconvolution, String> {
+        let peaks = if detect {
+            let detected = self.deconvolute_unconstrained(spectrum, false)?;
+            fitting::match_peaks(initial.lorentzians(), detected.lorentzians())
+        } else {
+            initial.lorentzians().to_vec()
+        };
+        let mask = self.fitting_mask(spectrum);
+
+        self.refine(spectrum, peaks, &[], &mask)
+    }
+
+    /// Fits one Lorentzian per target to the data points within the target
+    /// windows, without running the peak detection.
+    fn fit_targets(
+        &self,
+        spectrum: &Spectrum,
+        targets: &[targets::Target],
+    ) -> std::result::Result<deconvolution::Deconvolution, String> {
+        let chemical_shifts = spectrum.as_ref().chemical_shifts();
+        let intensities = spectrum.as_ref().intensities();
+        let peaks = targets
+            .iter()
+            .map(|target| targets::initial_guess(target, chemical_shifts, intensities))
+            .collect::<Vec<_>>();
+        let constraints = targets
+            .iter()
+            .map(targets::constraint)
+            .collect::<Vec<fitting::Constraint>>();
+        let mask = self
+            .fitting_mask(spectrum)
+            .into_iter()
+            .zip(targets::window_mask(targets, chemical_shifts))
+            .map(|(fitted, in_window)| fitted && in_window)
+            .collect::<Vec<bool>>();
+
+        self.refine(spectrum, peaks, &constraints, &mask)
+    }
+
+    /// Settings of the deconvoluter in the form returned by `settings()`. The
+    /// same value is recorded in the provenance of its deconvolutions, so the
+    /// recorded settings can be compared with those of a deconvoluter.
+    fn settings_record(&self) -> serde_json::Value {
+        let ignore_regions = self.inner.ignore_regions().map(|regions| {
+            regions
+                .iter()
+                .map(|region| serde_json::json!({ "start": region.0, "end": region.1 }))
+                .collect::<Vec<serde_json::Value>>()
+        });
+        let ignore_presets = self
+            .presets
+            .iter()
+            .map(|preset| serde_json::json!({ "name": preset.name, "nucleus": preset.nucleus }))
+            .collect::<Vec<serde_json::Value>>();
+
+        serde_json::json!({
+            "smoothing": self.smoothing_record(),
+            "selection": self.selection_record(),
+            "fitting": self.fitting_record(),
+            "ignore_regions": ignore_regions,
+            "ignore_presets": ignore_presets,
+            "fit_constraints": self.fit_constraints_record(),
+            "profile": self.profile,
+        })
+    }
+
+    fn smoothing_record(&self) -> serde_json::Value {
         match self.inner.smoothing_settings() {
+            deconvolution::SmoothingSettings::Identity => {
+                serde_json::json!({ "method": "Identity" })
+            }
             deconvolution::SmoothingSettings::MovingAverage {
                 iterations,
                 window_size,
-            } => {
-                let mut result = HashMap::<&str, Robj>::new();
-                result.insert("method", "Moving Average Filter".into());
-                result.insert("iterations", iterations.into());
-                result.insert("window_size", window_size.into());
-
-                List::from_hashmap(result)
-            }
+            } => serde_json::json!({
+                "method": "Moving Average Filter",
+                "iterations": iterations,
+                "window_size": window_size,
+            }),
             _ => {
                 throw_r_error(format!(
                     "Unknown smoothing settings, {:?}",
@@ -41,19 +459,19 @@ impl Deconvoluter {
         }
     }
 
-    pub(crate) fn selection_settings(&self) -> Result<List> {
+    fn selection_record(&self) -> serde_json::Value {
         match self.inner.selection_settings() {
+            deconvolution::SelectionSettings::DetectorOnly => {
+                serde_json::json!({ "method": "Detector Only" })
+            }
             deconvolution::SelectionSettings::NoiseScoreFilter {
                 scoring_method,
                 threshold,
-            } => {
-                let mut result = HashMap::<&str, Robj>::new();
-                result.insert("method", "Noise Score Filter".into());
-                result.insert("scoring_method", scoring_method.to_string().into());
-                result.insert("threshold", threshold.into());
-
-                List::from_hashmap(result)
-            }
+            } => serde_json::json!({
+                "method": "Noise Score Filter",
+                "scoring_method": scoring_method.to_string(),
+                "threshold": threshold,
+            }),
             _ => {
                 throw_r_error(format!(
                     "Unknown selection settings, {:?}",
@@ -63,15 +481,12 @@ impl Deconvoluter {
         }
     }
 
-    pub(crate) fn fitting_settings(&self) -> Result<List> {
+    fn fitting_record(&self) -> serde_json::Value {
         match self.inner.fitting_settings() {
-            deconvolution::FittingSettings::Analytical { iterations } => {
-                let mut result = HashMap::<&str, Robj>::new();
-                result.insert("method", "Analytical Fitter".into());
-                result.insert("iterations", iterations.into());
-
-                List::from_hashmap(result)
-            }
+            deconvolution::FittingSettings::Analytical { iterations } => serde_json::json!({
+                "method": "Analytical Fitter",
+                "iterations": iterations,
+            }),
             _ => {
                 throw_r_error(format!(
                     "Unknown fitting settings, {:?}",
@@ -81,28 +496,279 @@ impl Deconvoluter {
         }
     }
 
-    pub(crate) fn ignore_regions(&self) -> Nullable<List> {
-        if let Some(ignore_regions) = self.inner.ignore_regions() {
-            let ignore_regions: Vec<Robj> = ignore_regions
-                .iter()
-                .map(|(start, end)| {
-                    let mut result = HashMap::<&str, Robj>::new();
-                    result.insert("start", start.into());
-                    result.insert("end", end.into());
-
-                    List::from_hashmap(result).into()
+    fn fit_constraints_record(&self) -> serde_json::Value {
+        let multiplets = self
+            .bounds
+            .multiplets
+            .iter()
+            .map(|pattern| {
+                serde_json::json!({
+                    "centre": pattern.centre,
+                    "lines": pattern.lines,
+                    "coupling": pattern.coupling,
                 })
-                .collect();
+            })
+            .collect::<Vec<serde_json::Value>>();
+
+        serde_json::json!({
+            "half_width": self.bounds.half_width.map(|(min, max)| vec![min, max]),
+            "max_drift": self.bounds.max_drift,
+            "non_negative": !self.bounds.allow_negative,
+            "multiplets": multiplets,
+        })
+    }
+
+    /// Wraps the deconvolution of a spectrum together with its provenance.
+    fn record(
+        &self,
+        deconvolution: deconvolution::Deconvolution,
+        spectrum: &Spectrum,
+        parallel: bool,
+    ) -> Deconvolution {
+        self.record_as(deconvolution, spectrum, parallel, provenance::Mode::Untargeted)
+    }
+
+    /// Like [`Deconvoluter::record`], for a deconvolution obtained in the
+    /// given mode.
+    fn record_as(
+        &self,
+        deconvolution: deconvolution::Deconvolution,
+        spectrum: &Spectrum,
+        parallel: bool,
+        mode: provenance::Mode,
+    ) -> Deconvolution {
+        let threads = match (parallel, &self.threads) {
+            (false, _) => 1,
+            (true, Some(threads)) => threads.current_num_threads(),
+            (true, None) => rayon::current_num_threads(),
+        };
+        let provenance =
+            provenance::Provenance::new(self.settings_record(), threads, spectrum, mode);
+
+        Deconvolution::of_spectrum(deconvolution, spectrum, provenance)
+    }
 
-            NotNull(List::from_values(ignore_regions))
+    /// Whether the spectra must be deconvoluted one by one rather than with
+    /// the batch methods of the inner deconvoluter.
+    fn needs_individual_deconvolution(&self, spectra: &[Spectrum]) -> bool {
+        self.bounds.is_active()
+            || !self.presets.is_empty()
+            || spectra.iter().any(|spectrum| {
+                spectrum.disjoint_signal_regions().is_some()
+                    || spectrum.sample_ignore_regions().is_some()
+            })
+    }
+
+    /// Merges the deconvolutions of the signal regions, keeping the
+    /// Lorentzians centred within their region. The MSE is the average of the
+    /// regions' MSEs, weighted by their number of data points.
+    fn merge(
+        &self,
+        spectrum: &Spectrum,
+        regions: &[(f64, f64)],
+        deconvolutions: Vec<deconvolution::Deconvolution>,
+    ) -> deconvolution::Deconvolution {
+        let mut lorentzians = Vec::new();
+        let mut weighted_mse = 0.0;
+        let mut total = 0usize;
+        regions
+            .iter()
+            .zip(deconvolutions.iter())
+            .for_each(|((start, end), deconvolution)| {
+                let (lower, upper) = (start.min(*end), start.max(*end));
+                lorentzians.extend(deconvolution.lorentzians().iter().filter(|lorentzian| {
+                    lorentzian.maxp() >= lower && lorentzian.maxp() <= upper
+                }));
+                let points = spectrum
+                    .as_ref()
+                    .chemical_shifts()
+                    .iter()
+                    .filter(|x| **x >= lower && **x <= upper)
+                    .count();
+                weighted_mse += deconvolution.mse() * points as f64;
+                total += points;
+            });
+        let mse = if total > 0 {
+            weighted_mse / total as f64
         } else {
-            Null
+            0.0
+        };
+
+        deconvolution::Deconvolution::new(
+            lorentzians,
+            self.inner.smoothing_settings(),
+            self.inner.selection_settings(),
+            self.inner.fitting_settings(),
+            mse,
+        )
+    }
+}
+
+/// @eval make_r_docs("Deconvoluter")
+#[extendr]
+impl Deconvoluter {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn from_profile(name: &str) -> Self {
+        match profiles::lookup(name).and_then(|profile| Self::with_profile(&profile)) {
+            Ok(deconvoluter) => deconvoluter,
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    pub(crate) fn profiles() -> Result<Robj> {
+        let profiles = profiles::all()?;
+        let columns: Vec<(&str, Robj)> = vec![
+            (
+                "profile",
+                profiles
+                    .iter()
+                    .map(|profile| profile.name.clone())
+                    .collect::<Vec<String>>()
+                    .into(),
+            ),
+            (
+                "nucleus",
+                profiles
+                    .iter()
+                    .map(|profile| profile.nucleus.clone())
+                    .collect::<Vec<String>>()
+                    .into(),
+            ),
+            (
+                "frequency",
+                profiles
+                    .iter()
+                    .map(|profile| profile.frequency)
+                    .collect::<Vec<Option<f64>>>()
+                    .into(),
+            ),
+            (
+                "smoothing_iterations",
+                profiles
+                    .iter()
+                    .map(|profile| profile.smoothing_iterations)
+                    .collect::<Vec<usize>>()
+                    .into(),
+            ),
+            (
+                "smoothing_window_size",
+                profiles
+                    .iter()
+                    .map(|profile| profile.smoothing_window_size)
+                    .collect::<Vec<usize>>()
+                    .into(),
+            ),
+            (
+                "noise_threshold",
+                profiles
+                    .iter()
+                    .map(|profile| profile.noise_threshold)
+                    .collect::<Vec<f64>>()
+                    .into(),
+            ),
+            (
+                "fitting_iterations",
+                profiles
+                    .iter()
+                    .map(|profile| profile.fitting_iterations)
+                    .collect::<Vec<usize>>()
+                    .into(),
+            ),
+            (
+                "ignore_presets",
+                profiles
+                    .iter()
+                    .map(|profile| profile.ignore_presets.join(", "))
+                    .collect::<Vec<String>>()
+                    .into(),
+            ),
+            (
+                "example",
+                profiles
+                    .iter()
+                    .map(|profile| profile.example)
+                    .collect::<Vec<bool>>()
+                    .into(),
+            ),
+        ];
+
+        util::data_frame(columns, profiles.len())
+    }
+
+    pub(crate) fn register_profiles(path: &str) -> usize {
+        match profiles::register(path) {
+            Ok(count) => count,
+            Err(error) => throw_r_error(error.to_string()),
         }
     }
 
+    pub(crate) fn settings(&self) -> Result<List> {
+        provenance::json_to_list(&self.settings_record())
+    }
+
+    pub(crate) fn smoothing_settings(&self) -> Result<List> {
+        provenance::json_to_list(&self.smoothing_record())
+    }
+
+    pub(crate) fn selection_settings(&self) -> Result<List> {
+        provenance::json_to_list(&self.selection_record())
+    }
+
+    pub(crate) fn fitting_settings(&self) -> Result<List> {
+        provenance::json_to_list(&self.fitting_record())
+    }
+
+    pub(crate) fn ignore_regions(&self, frequency: Nullable<f64>) -> Result<Nullable<List>> {
+        let frequency = match frequency {
+            NotNull(frequency) if frequency > 0.0 => frequency,
+            NotNull(_) => return Err(Error::from("frequency must be positive")),
+            Null => presets::DEFAULT_FREQUENCY,
+        };
+        let regions = self
+            .inner
+            .ignore_regions()
+            .into_iter()
+            .flatten()
+            .map(|region| (*region, None))
+            .chain(self.presets.iter().flat_map(|preset| {
+                preset
+                    .at_frequency(frequency)
+                    .into_iter()
+                    .map(move |region| (region, Some(preset)))
+            }))
+            .map(|((start, end), preset)| {
+                let mut result = HashMap::<&str, Robj>::new();
+                result.insert("start", start.into());
+                result.insert("end", end.into());
+                result.insert(
+                    "preset",
+                    vec![preset.map(|preset| preset.name.clone())].into(),
+                );
+                result.insert(
+                    "nucleus",
+                    vec![preset.map(|preset| preset.nucleus.clone())].into(),
+                );
+
+                List::from_hashmap(result).map(Robj::from)
+            })
+            .collect::<Result<Vec<Robj>>>()?;
+
+        Ok(match regions.is_empty() {
+            true => Null,
+            false => NotNull(List::from_values(regions)),
+        })
+    }
+
+    pub(crate) fn fit_constraints(&self) -> Result<List> {
+        provenance::json_to_list(&self.fit_constraints_record())
+    }
+
     pub(crate) fn set_identity_smoother(&mut self) {
         match self.inner.set_smoothing_settings(deconvolution::SmoothingSettings::Identity) {
-            Ok(_) => (),
+            Ok(_) => self.profile = None,
             Err(error) => throw_r_error(error.to_string()),
         }
     }
@@ -114,14 +780,14 @@ impl Deconvoluter {
                 iterations,
                 window_size,
             }) {
-            Ok(_) => (),
+            Ok(_) => self.profile = None,
             Err(error) => throw_r_error(error.to_string()),
         }
     }
 
     pub(crate) fn set_detector_only(&mut self) {
         match self.inner.set_selection_settings(deconvolution::SelectionSettings::DetectorOnly) {
-            Ok(_) => (),
+            Ok(_) => self.profile = None,
             Err(error) => throw_r_error(error.to_string()),
         }
     }
@@ -133,7 +799,7 @@ impl Deconvoluter {
                 threshold,
             },
         ) {
-            Ok(_) => (),
+            Ok(_) => self.profile = None,
             Err(error) => throw_r_error(error.to_string()),
         }
     }
@@ -143,20 +809,100 @@ impl Deconvoluter {
             .inner
             .set_fitting_settings(deconvolution::FittingSettings::Analytical { iterations })
         {
-            Ok(_) => (),
+            Ok(_) => self.profile = None,
             Err(error) => throw_r_error(error.to_string()),
         }
     }
 
+    pub(crate) fn set_half_width_bounds(&mut self, min: f64, max: f64) {
+        if !(min >= 0.0 && min < max) {
+            throw_r_error("half width bounds must satisfy 0 <= min < max");
+        }
+        self.bounds.half_width = Some((min, max));
+    }
+
+    pub(crate) fn set_max_drift(&mut self, max_drift: f64) {
+        if !(max_drift.is_finite() && max_drift > 0.0) {
+            throw_r_error("maximum drift must be a positive number");
+        }
+        self.bounds.max_drift = Some(max_drift);
+    }
+
+    pub(crate) fn set_non_negative(&mut self, non_negative: bool) {
+        self.bounds.allow_negative = !non_negative;
+    }
+
+    pub(crate) fn add_multiplet_constraint(&mut self, centre: f64, lines: usize, coupling: f64) {
+        if lines == 0 {
+            throw_r_error("a multiplet must have at least one line");
+        }
+        if lines > 1 && !(coupling.is_finite() && coupling > 0.0) {
+            throw_r_error("coupling constant must be a positive number");
+        }
+        self.bounds.multiplets.push(fitting::MultipletPattern {
+            centre,
+            lines,
+            coupling,
+        });
+    }
+
+    pub(crate) fn clear_fit_constraints(&mut self) {
+        self.bounds = fitting::Bounds::default();
+    }
+
     pub(crate) fn add_ignore_region(&mut self, start: f64, end: f64) {
         match self.inner.add_ignore_region((start, end)) {
-            Ok(_) => (),
+            Ok(_) => self.profile = None,
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    pub(crate) fn add_ignore_preset(&mut self, name: &str, nucleus: &str) {
+        match presets::lookup(name, nucleus) {
+            Ok(preset) => {
+                self.presets.push(preset);
+                self.profile = None;
+            }
             Err(error) => throw_r_error(error.to_string()),
         }
     }
 
     pub(crate) fn clear_ignore_regions(&mut self) {
         self.inner.clear_ignore_regions();
+        self.presets.clear();
+        self.profile = None;
+    }
+
+    pub(crate) fn ignore_presets() -> Result<Robj> {
+        let presets = presets::all()?;
+        let rows = presets
+            .iter()
+            .flat_map(|preset| {
+                preset
+                    .regions
+                    .iter()
+                    .map(move |region| (preset.name.clone(), preset.nucleus.clone(), *region))
+            })
+            .collect::<Vec<_>>();
+        let preset = rows.iter().map(|row| row.0.clone()).collect::<Vec<String>>();
+        let nucleus = rows.iter().map(|row| row.1.clone()).collect::<Vec<String>>();
+        let centre = rows.iter().map(|row| row.2.centre).collect::<Vec<f64>>();
+        let width = rows.iter().map(|row| row.2.width).collect::<Vec<f64>>();
+        let columns: Vec<(&str, Robj)> = vec![
+            ("preset", preset.into()),
+            ("nucleus", nucleus.into()),
+            ("centre", centre.into()),
+            ("width_hz", width.into()),
+        ];
+
+        util::data_frame(columns, rows.len())
+    }
+
+    pub(crate) fn register_ignore_presets(path: &str) -> usize {
+        match presets::register(path) {
+            Ok(count) => count,
+            Err(error) => throw_r_error(error.to_string()),
+        }
     }
 
     /// WARNING: These persist when the object is cloned, meaning that two
@@ -181,22 +927,20 @@ impl Deconvoluter {
     }
 
     pub(crate) fn deconvolute_spectrum(&self, spectrum: &Spectrum) -> Deconvolution {
-        match self.inner.deconvolute_spectrum(spectrum.as_ref()) {
-            Ok(deconvolution) => deconvolution.into(),
-            Err(error) => throw_r_error(error.to_string()),
+        match self.deconvolute(spectrum, false) {
+            Ok(deconvolution) => self.record(deconvolution, spectrum, false),
+            Err(error) => throw_r_error(error),
         }
     }
 
     pub(crate) fn par_deconvolute_spectrum(&self, spectrum: &Spectrum) -> Deconvolution {
         let deconvolution = match &self.threads {
-            Some(threads) => threads.install(|| {
-                self.inner.par_deconvolute_spectrum(spectrum.as_ref())
-            }),
-            None => self.inner.par_deconvolute_spectrum(spectrum.as_ref()),
+            Some(threads) => threads.install(|| self.deconvolute(spectrum, true)),
+            None => self.deconvolute(spectrum, true),
         };
 
         match deconvolution {
-            Ok(deconvolution) => deconvolution.into(),
+            Ok(deconvolution) => self.record(deconvolution, spectrum, true),
             Err(error) => throw_r_error(error.to_string()),
         }
     }
@@ -206,10 +950,22 @@ impl Deconvoluter {
             Ok(spectra) => spectra,
             Err(error) => throw_r_error(error.to_string()),
         };
-        let deconvolutions = match self.inner.deconvolute_spectra(&spectra) {
+        let deconvolutions = if self.needs_individual_deconvolution(&spectra) {
+            spectra
+                .iter()
+                .map(|spectrum| self.deconvolute(spectrum, false))
+                .collect::<std::result::Result<Vec<_>, String>>()
+        } else {
+            self.inner
+                .deconvolute_spectra(&spectra)
+                .map_err(|error| error.to_string())
+                .and_then(|deconvolutions| self.enforce_all(&spectra, deconvolutions))
+        };
+        let deconvolutions = match deconvolutions {
             Ok(deconvolutions) => deconvolutions
                 .into_iter()
-                .map(|deconvolution| deconvolution.into())
+                .zip(spectra.iter())
+                .map(|(deconvolution, spectrum)| self.record(deconvolution, spectrum, false))
                 .collect::<Vec<Deconvolution>>(),
             Err(error) => throw_r_error(error.to_string()),
         };
@@ -222,16 +978,28 @@ impl Deconvoluter {
             Ok(spectra) => spectra,
             Err(error) => throw_r_error(error.to_string()),
         };
+        let par_deconvolute_spectra = || {
+            if self.needs_individual_deconvolution(&spectra) {
+                spectra
+                    .par_iter()
+                    .map(|spectrum| self.deconvolute(spectrum, false))
+                    .collect::<std::result::Result<Vec<_>, String>>()
+            } else {
+                self.inner
+                    .par_deconvolute_spectra(&spectra)
+                    .map_err(|error| error.to_string())
+                    .and_then(|deconvolutions| self.enforce_all(&spectra, deconvolutions))
+            }
+        };
         let deconvolutions = match &self.threads {
-            Some(threads) => threads.install(|| {
-                self.inner.par_deconvolute_spectra(&spectra)
-            }),
-            None => self.inner.par_deconvolute_spectra(&spectra),
+            Some(threads) => threads.install(par_deconvolute_spectra),
+            None => par_deconvolute_spectra(),
         };
         let deconvolutions = match deconvolutions {
             Ok(deconvolutions) => deconvolutions
                 .into_iter()
-                .map(|deconvolution| deconvolution.into())
+                .zip(spectra.iter())
+                .map(|(deconvolution, spectrum)| self.record(deconvolution, spectrum, true))
                 .collect::<Vec<Deconvolution>>(),
             Err(error) => throw_r_error(error.to_string()),
         };
@@ -239,12 +1007,173 @@ impl Deconvoluter {
         List::from_values(deconvolutions)
     }
 
+    pub(crate) fn deconvolute_spectrum_from(
+        &self,
+        spectrum: &Spectrum,
+        initial: &Deconvolution,
+        detect: bool,
+    ) -> Deconvolution {
+        let warm_start = || provenance::Mode::warm_start(initial.as_ref().lorentzians(), detect);
+
+        match self.deconvolute_from(spectrum, initial.as_ref(), detect) {
+            Ok(deconvolution) => self.record_as(deconvolution, spectrum, false, warm_start()),
+            Err(error) => throw_r_error(error),
+        }
+    }
+
+    pub(crate) fn deconvolute_spectra_from(
+        &self,
+        spectra: List,
+        initial: &Deconvolution,
+        detect: bool,
+    ) -> List {
+        let warm_start = || provenance::Mode::warm_start(initial.as_ref().lorentzians(), detect);
+        let spectra = match Spectrum::recover_list(&spectra) {
+            Ok(spectra) => spectra,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let deconvolutions = match spectra
+            .iter()
+            .map(|spectrum| self.deconvolute_from(spectrum, initial.as_ref(), detect))
+            .collect::<std::result::Result<Vec<_>, String>>()
+        {
+            Ok(deconvolutions) => deconvolutions
+                .into_iter()
+                .zip(spectra.iter())
+                .map(|(deconvolution, spectrum)| {
+                    self.record_as(deconvolution, spectrum, false, warm_start())
+                })
+                .collect::<Vec<Deconvolution>>(),
+            Err(error) => throw_r_error(error),
+        };
+
+        List::from_values(deconvolutions)
+    }
+
+    pub(crate) fn par_deconvolute_spectra_from(
+        &self,
+        spectra: List,
+        initial: &Deconvolution,
+        detect: bool,
+    ) -> List {
+        let warm_start = || provenance::Mode::warm_start(initial.as_ref().lorentzians(), detect);
+        let spectra = match Spectrum::recover_list(&spectra) {
+            Ok(spectra) => spectra,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let par_deconvolute_spectra_from = || {
+            spectra
+                .par_iter()
+                .map(|spectrum| self.deconvolute_from(spectrum, initial.as_ref(), detect))
+                .collect::<std::result::Result<Vec<_>, String>>()
+        };
+        let deconvolutions = match &self.threads {
+            Some(threads) => threads.install(par_deconvolute_spectra_from),
+            None => par_deconvolute_spectra_from(),
+        };
+        let deconvolutions = match deconvolutions {
+            Ok(deconvolutions) => deconvolutions
+                .into_iter()
+                .zip(spectra.iter())
+                .map(|(deconvolution, spectrum)| {
+                    self.record_as(deconvolution, spectrum, true, warm_start())
+                })
+                .collect::<Vec<Deconvolution>>(),
+            Err(error) => throw_r_error(error),
+        };
+
+        List::from_values(deconvolutions)
+    }
+
+    pub(crate) fn deconvolute_targets(&self, spectrum: &Spectrum, targets: List) -> Deconvolution {
+        let targets = match targets::targets_from_list(&targets) {
+            Ok(targets) => targets,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+
+        match self.fit_targets(spectrum, &targets) {
+            Ok(deconvolution) => {
+                let mode = provenance::Mode::Targeted { targets };
+                self.record_as(deconvolution, spectrum, false, mode)
+            }
+            Err(error) => throw_r_error(error),
+        }
+    }
+
+    /// DEPRECATED: Replaces the settings with the best ones found for the
+    /// reference and returns only the MSE of that fit. Use `search_settings()`
+    /// instead, which returns every evaluated candidate and only changes the
+    /// settings if `apply = TRUE`.
     pub(crate) fn optimize_settings(&mut self, reference: &Spectrum) -> f64 {
         match self.inner.optimize_settings(reference.as_ref()) {
-            Ok(mse) => mse,
+            Ok(mse) => {
+                self.profile = None;
+                mse
+            }
             Err(error) => throw_r_error(error.to_string()),
         }
     }
+
+    pub(crate) fn search_settings(
+        &mut self,
+        reference: &Spectrum,
+        search_space: Nullable<List>,
+        objective: &str,
+        penalty: f64,
+        apply: bool,
+    ) -> Result<List> {
+        let search = optimization::Search::new(search_space, objective, penalty, "mean", Null, 1)?;
+
+        self.search(std::slice::from_ref(reference), &search, apply, false)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn search_settings_set(
+        &mut self,
+        references: List,
+        search_space: Nullable<List>,
+        objective: &str,
+        penalty: f64,
+        aggregate: &str,
+        folds: Nullable<f64>,
+        apply: bool,
+    ) -> Result<List> {
+        let references = Spectrum::recover_list(&references)?;
+        let search = optimization::Search::new(
+            search_space,
+            objective,
+            penalty,
+            aggregate,
+            folds,
+            references.len(),
+        )?;
+
+        self.search(&references, &search, apply, false)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn par_search_settings_set(
+        &mut self,
+        references: List,
+        search_space: Nullable<List>,
+        objective: &str,
+        penalty: f64,
+        aggregate: &str,
+        folds: Nullable<f64>,
+        apply: bool,
+    ) -> Result<List> {
+        let references = Spectrum::recover_list(&references)?;
+        let search = optimization::Search::new(
+            search_space,
+            objective,
+            penalty,
+            aggregate,
+            folds,
+            references.len(),
+        )?;
+
+        self.search(&references, &search, apply, true)
+    }
 }
 
 extendr_module! {
@@ -1,10 +1,22 @@
+use crate::annotations;
+use crate::library;
+use crate::multiplets;
+use crate::provenance;
+use crate::spectrum::Spectrum;
+use crate::util;
 use extendr_api::prelude::*;
 use metabodecon::deconvolution;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct Deconvolution {
+    #[serde(flatten)]
     inner: deconvolution::Deconvolution,
+    #[serde(default)]
+    annotations: annotations::Annotations,
+    #[serde(default)]
+    provenance: Option<provenance::Provenance>,
 }
 
 impl AsRef<deconvolution::Deconvolution> for Deconvolution {
@@ -15,10 +27,33 @@ impl AsRef<deconvolution::Deconvolution> for Deconvolution {
 
 impl From<deconvolution::Deconvolution> for Deconvolution {
     fn from(value: deconvolution::Deconvolution) -> Self {
-        Self { inner: value }
+        Self {
+            inner: value,
+            annotations: annotations::Annotations::new(),
+            provenance: None,
+        }
     }
 }
 
+impl Deconvolution {
+    /// Wraps the deconvolution of a spectrum, carrying over its annotations
+    /// and recording how it was made.
+    pub(crate) fn of_spectrum(
+        value: deconvolution::Deconvolution,
+        spectrum: &Spectrum,
+        provenance: provenance::Provenance,
+    ) -> Self {
+        Self {
+            inner: value,
+            annotations: spectrum.sample_annotations().clone(),
+            provenance: Some(provenance),
+        }
+    }
+
+    /// Fields added to `metabodecon::deconvolution::Deconvolution`.
+    const FIELDS: [&'static str; 2] = ["annotations", "provenance"];
+}
+
 /// @eval make_r_docs("Deconvolution")
 #[extendr]
 impl Deconvolution {
@@ -40,6 +75,109 @@ impl Deconvolution {
         List::from_hashmap(result)
     }
 
+    pub(crate) fn peak_table(&self, frequency: f64) -> Result<Robj> {
+        if !(frequency.is_finite() && frequency > 0.0) {
+            throw_r_error("frequency must be a positive number");
+        }
+        let mut lorentzians = self.inner.lorentzians().to_vec();
+        lorentzians.sort_by(|a, b| a.maxp().total_cmp(&b.maxp()));
+        let len = lorentzians.len();
+        let mut position_ppm = Vec::<f64>::with_capacity(len);
+        let mut position_hz = Vec::<f64>::with_capacity(len);
+        let mut height = Vec::<f64>::with_capacity(len);
+        let mut fwhm_ppm = Vec::<f64>::with_capacity(len);
+        let mut fwhm_hz = Vec::<f64>::with_capacity(len);
+        let mut area = Vec::<f64>::with_capacity(len);
+        let mut sf = Vec::<f64>::with_capacity(len);
+        let mut hw = Vec::<f64>::with_capacity(len);
+        let mut maxp = Vec::<f64>::with_capacity(len);
+        lorentzians.iter().for_each(|lorentzian| {
+            position_ppm.push(lorentzian.maxp());
+            position_hz.push(lorentzian.maxp() * frequency);
+            height.push(lorentzian.sf() / lorentzian.hw());
+            fwhm_ppm.push(2.0 * lorentzian.hw());
+            fwhm_hz.push(2.0 * lorentzian.hw() * frequency);
+            area.push(std::f64::consts::PI * lorentzian.sf());
+            sf.push(lorentzian.sf());
+            hw.push(lorentzian.hw());
+            maxp.push(lorentzian.maxp());
+        });
+        let columns: Vec<(&str, Robj)> = vec![
+            ("position_ppm", position_ppm.into()),
+            ("position_hz", position_hz.into()),
+            ("height", height.into()),
+            ("fwhm_ppm", fwhm_ppm.into()),
+            ("fwhm_hz", fwhm_hz.into()),
+            ("area", area.into()),
+            ("sf", sf.clone().into()),
+            ("hw", hw.clone().into()),
+            ("maxp", maxp.clone().into()),
+            ("A", sf.into()),
+            ("lambda", hw.into()),
+            ("x0", maxp.into()),
+        ];
+
+        util::data_frame(columns, len)
+    }
+
+    pub(crate) fn multiplets(&self, frequency: f64, tolerances: Nullable<List>) -> Result<List> {
+        if !(frequency.is_finite() && frequency > 0.0) {
+            throw_r_error("frequency must be a positive number");
+        }
+        let tolerances = match tolerances {
+            NotNull(tolerances) => multiplets::Tolerances::try_from(tolerances)?,
+            Null => multiplets::Tolerances::default(),
+        };
+        let lorentzians = self.inner.lorentzians();
+        let multiplets = multiplets::group(lorentzians, frequency, &tolerances);
+
+        multiplets::to_list(&multiplets, lorentzians, frequency)
+    }
+
+    pub(crate) fn identify_metabolites(
+        &self,
+        library: &str,
+        frequency: f64,
+        tolerances: Nullable<List>,
+    ) -> Result<List> {
+        if !(frequency.is_finite() && frequency > 0.0) {
+            throw_r_error("frequency must be a positive number");
+        }
+        let tolerances = match tolerances {
+            NotNull(tolerances) => multiplets::Tolerances::try_from(tolerances)?,
+            Null => multiplets::Tolerances::default(),
+        };
+        let metabolites = library::read(library)?;
+
+        library::identify(&metabolites, self.inner.lorentzians(), frequency, &tolerances)
+    }
+
+    pub(crate) fn annotations(&self) -> Result<List> {
+        annotations::to_list(&self.annotations)
+    }
+
+    pub(crate) fn set_annotations(&mut self, annotations: List) {
+        match annotations::from_list(&annotations) {
+            Ok(annotations) => self.annotations.extend(annotations),
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    pub(crate) fn remove_annotation(&mut self, name: &str) {
+        self.annotations.remove(name);
+    }
+
+    pub(crate) fn clear_annotations(&mut self) {
+        self.annotations.clear();
+    }
+
+    pub(crate) fn provenance(&self) -> Result<Nullable<List>> {
+        match &self.provenance {
+            Some(provenance) => Ok(NotNull(provenance.to_list()?)),
+            None => Ok(Null),
+        }
+    }
+
     pub(crate) fn mse(&self) -> f64 {
         self.inner.mse()
     }
@@ -60,7 +198,7 @@ impl Deconvolution {
     }
 
     pub(crate) fn write_json(&self, path: &str) {
-        let serialized = match serde_json::to_string_pretty(self.as_ref()) {
+        let serialized = match serde_json::to_string_pretty(self) {
             Ok(serialized) => serialized,
             Err(error) => throw_r_error(error.to_string()),
         };
@@ -70,14 +208,21 @@ impl Deconvolution {
     pub(crate) fn read_json(path: &str) -> Self {
         let serialized = std::fs::read_to_string(path).unwrap();
 
-        match serde_json::from_str::<deconvolution::Deconvolution>(&serialized) {
-            Ok(deserialized) => deserialized.into(),
-            Err(error) => throw_r_error(error.to_string()),
+        let deconvolution = util::deserialize_with(
+            || serde_json::from_str::<Deconvolution>(&serialized),
+            || serde_json::from_str::<deconvolution::Deconvolution>(&serialized),
+            || serde_json::from_str(&serialized),
+            &Self::FIELDS,
+        );
+
+        match deconvolution {
+            Ok(deconvolution) => deconvolution,
+            Err(error) => throw_r_error(error),
         }
     }
 
     pub(crate) fn write_bin(&self, path: &str) {
-        let serialized = match rmp_serde::to_vec(self.as_ref()) {
+        let serialized = match rmp_serde::to_vec(self) {
             Ok(serialized) => serialized,
             Err(error) => throw_r_error(error.to_string()),
         };
@@ -87,9 +232,16 @@ impl Deconvolution {
     pub(crate) fn read_bin(path: &str) -> Self {
         let serialized = std::fs::read(path).unwrap();
 
-        match rmp_serde::from_slice::<deconvolution::Deconvolution>(&serialized) {
-            Ok(deserialized) => deserialized.into(),
-            Err(error) => throw_r_error(error.to_string()),
+        let deconvolution = util::deserialize_with(
+            || rmp_serde::from_slice::<Deconvolution>(&serialized),
+            || rmp_serde::from_slice::<deconvolution::Deconvolution>(&serialized),
+            || rmp_serde::from_slice(&serialized),
+            &Self::FIELDS,
+        );
+
+        match deconvolution {
+            Ok(deconvolution) => deconvolution,
+            Err(error) => throw_r_error(error),
         }
     }
 }
@@ -0,0 +1,624 @@
+use metabodecon::deconvolution::Lorentzian;
+
+/// Half width of the window around a peak used to refine it, in multiples of
+/// its half width at half maximum.
+const WINDOW: f64 = 8.0;
+
+/// Half width of the window around a peak within which it contributes to the
+/// model during refinement, in multiples of its half width at half maximum.
+/// Beyond it a Lorentzian has fallen below 1e-4 of its maximum.
+const SUPPORT: f64 = 100.0;
+
+/// Levenberg-Marquardt steps per peak and sweep.
+const STEPS: usize = 4;
+
+/// Smallest half width a refined peak may take, relative to the spacing of the
+/// data points.
+const MIN_HALF_WIDTH: f64 = 0.1;
+
+/// Lorentzian `sf * hw / (hw^2 + (x - maxp)^2)`.
+fn lorentzian(sf: f64, hw: f64, maxp: f64) -> Lorentzian {
+    Lorentzian::new(sf * hw, hw.powi(2), maxp)
+}
+
+/// Range of the indices of the points of the sorted axis `x`, ascending or
+/// descending, that lie within `[lower, upper]`.
+fn index_range(x: &[f64], lower: f64, upper: f64) -> std::ops::Range<usize> {
+    let ascending = x.first() <= x.last();
+    let (start, end) = match ascending {
+        true => (
+            x.partition_point(|x| *x < lower),
+            x.partition_point(|x| *x <= upper),
+        ),
+        false => (
+            x.partition_point(|x| *x > upper),
+            x.partition_point(|x| *x >= lower),
+        ),
+    };
+
+    start..end.max(start)
+}
+
+/// Bounds on the parameters of a peak during refinement. Unset bounds default
+/// to a range around the current position and a positive half width.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct Constraint {
+    /// Range of the position in ppm.
+    pub(crate) position: Option<(f64, f64)>,
+    /// Range of the half width at half maximum in ppm.
+    pub(crate) half_width: Option<(f64, f64)>,
+    /// Whether the scale factor may become negative.
+    pub(crate) allow_negative: bool,
+    /// Whether the peak is kept as it is rather than refined.
+    pub(crate) fixed: bool,
+}
+
+impl Constraint {
+    /// Combines two constraints, keeping the narrower of each bound.
+    pub(crate) fn and(&self, other: &Constraint) -> Constraint {
+        Constraint {
+            position: intersect(self.position, other.position),
+            half_width: intersect(self.half_width, other.half_width),
+            allow_negative: self.allow_negative && other.allow_negative,
+            fixed: self.fixed || other.fixed,
+        }
+    }
+}
+
+/// Peaks that are fitted jointly, sharing a half width and keeping fixed
+/// `offsets` in ppm from a common centre, e.g. the lines of a multiplet.
+/// `members` are indices into the refined Lorentzians.
+#[derive(Clone, Debug)]
+pub(crate) struct Multiplet {
+    pub(crate) members: Vec<usize>,
+    pub(crate) offsets: Vec<f64>,
+}
+
+/// Group of Lorentzians fitted together, with the parameters
+/// `[centre, hw, sf_1, ..., sf_n]`. A single peak is a group with one member
+/// and offset zero.
+struct Unit {
+    members: Vec<usize>,
+    offsets: Vec<f64>,
+    constraint: Constraint,
+    parameters: Vec<f64>,
+}
+
+impl Unit {
+    fn evaluate(parameters: &[f64], offsets: &[f64], x: f64) -> f64 {
+        let (centre, hw) = (parameters[0], parameters[1]);
+        offsets
+            .iter()
+            .zip(parameters[2..].iter())
+            .map(|(offset, sf)| lorentzian(*sf, hw, centre + offset).evaluate(x))
+            .sum()
+    }
+
+    /// Range of the points of `x` within `reach` of the outermost lines.
+    fn range(parameters: &[f64], offsets: &[f64], x: &[f64], reach: f64) -> std::ops::Range<usize> {
+        let centre = parameters[0];
+        let first = offsets.iter().copied().fold(f64::INFINITY, f64::min);
+        let last = offsets.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+
+        index_range(x, centre + first - reach, centre + last + reach)
+    }
+
+    /// Adds `sign` times the unit to `model` within its support.
+    fn add_to(parameters: &[f64], offsets: &[f64], x: &[f64], model: &mut [f64], sign: f64) {
+        let range = Self::range(parameters, offsets, x, SUPPORT * parameters[1]);
+        model[range.clone()]
+            .iter_mut()
+            .zip(x[range].iter())
+            .for_each(|(model, x)| *model += sign * Self::evaluate(parameters, offsets, *x));
+    }
+
+    fn gradient(parameters: &[f64], offsets: &[f64], x: f64) -> Vec<f64> {
+        let (centre, hw) = (parameters[0], parameters[1]);
+        let mut gradient = vec![0.0; parameters.len()];
+        offsets
+            .iter()
+            .zip(parameters[2..].iter())
+            .enumerate()
+            .for_each(|(k, (offset, sf))| {
+                let d = x - centre - offset;
+                let denominator = hw.powi(2) + d.powi(2);
+                gradient[0] += 2.0 * sf * hw * d / denominator.powi(2);
+                gradient[1] += sf * (d.powi(2) - hw.powi(2)) / denominator.powi(2);
+                gradient[k + 2] = hw / denominator;
+            });
+
+        gradient
+    }
+
+    fn lorentzians(&self) -> impl Iterator<Item = (usize, Lorentzian)> + '_ {
+        let (centre, hw) = (self.parameters[0], self.parameters[1]);
+        self.members
+            .iter()
+            .zip(self.offsets.iter())
+            .zip(self.parameters[2..].iter())
+            .map(move |((member, offset), sf)| (*member, lorentzian(*sf, hw, centre + offset)))
+    }
+}
+
+/// Solves the linear system `a * x = b` by Gaussian elimination with partial
+/// pivoting.
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for column in 0..n {
+        let pivot =
+            (column..n).max_by(|i, j| a[*i][column].abs().total_cmp(&a[*j][column].abs()))?;
+        if a[pivot][column].is_nan() || a[pivot][column].abs() <= f64::MIN_POSITIVE {
+            return None;
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        let pivot_row = a[column].clone();
+        for row in column + 1..n {
+            let factor = a[row][column] / pivot_row[column];
+            a[row]
+                .iter_mut()
+                .zip(pivot_row.iter())
+                .skip(column)
+                .for_each(|(value, pivot)| *value -= factor * pivot);
+            b[row] -= factor * b[column];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum = (row + 1..n).map(|k| a[row][k] * x[k]).sum::<f64>();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+
+    x.iter().all(|x| x.is_finite()).then_some(x)
+}
+
+/// Fits a unit to `target` at the points `x` with a few Levenberg-Marquardt
+/// steps, keeping the centre and half width within the given ranges.
+fn fit_unit(
+    mut parameters: Vec<f64>,
+    offsets: &[f64],
+    x: &[f64],
+    target: &[f64],
+    position: (f64, f64),
+    half_width: (f64, f64),
+    allow_negative: bool,
+) -> Vec<f64> {
+    let sse = |parameters: &[f64]| {
+        x.iter()
+            .zip(target.iter())
+            .map(|(x, y)| (y - Unit::evaluate(parameters, offsets, *x)).powi(2))
+            .sum::<f64>()
+    };
+    let m = parameters.len();
+    let mut current = sse(&parameters);
+    let mut lambda = 1e-3;
+    for _ in 0..STEPS {
+        let mut jtj = vec![vec![0.0; m]; m];
+        let mut jtr = vec![0.0; m];
+        x.iter().zip(target.iter()).for_each(|(x, y)| {
+            let g = Unit::gradient(&parameters, offsets, *x);
+            let r = y - Unit::evaluate(&parameters, offsets, *x);
+            for i in 0..m {
+                jtr[i] += g[i] * r;
+                for j in 0..m {
+                    jtj[i][j] += g[i] * g[j];
+                }
+            }
+        });
+        let mut improved = false;
+        while lambda < 1e8 {
+            let mut a = jtj.clone();
+            (0..m).for_each(|i| a[i][i] *= 1.0 + lambda);
+            let step = match solve(a, jtr.clone()) {
+                Some(step) => step,
+                None => break,
+            };
+            let candidate = parameters
+                .iter()
+                .zip(step.iter())
+                .map(|(p, s)| p + s)
+                .collect::<Vec<f64>>();
+            let valid = candidate[0] >= position.0
+                && candidate[0] <= position.1
+                && candidate[1] >= half_width.0
+                && candidate[1] <= half_width.1
+                && (allow_negative || candidate[2..].iter().all(|sf| *sf > 0.0));
+            if valid {
+                let candidate_sse = sse(&candidate);
+                if candidate_sse < current {
+                    parameters = candidate;
+                    current = candidate_sse;
+                    lambda = (lambda / 10.0).max(1e-7);
+                    improved = true;
+                    break;
+                }
+            }
+            lambda *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+
+    parameters
+}
+
+fn intersect(a: Option<(f64, f64)>, b: Option<(f64, f64)>) -> Option<(f64, f64)> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some((a.0.max(b.0), a.1.min(b.1))),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Groups the Lorentzians into units and moves their initial parameters
+/// into the allowed ranges. The half width range of every unit is resolved,
+/// raising its lower bound to [`MIN_HALF_WIDTH`] times the `spacing` of the
+/// data points, which fails if the upper bound is below it.
+fn units(
+    lorentzians: &[Lorentzian],
+    constraints: &[Constraint],
+    multiplets: &[Multiplet],
+    spacing: f64,
+) -> std::result::Result<Vec<Unit>, String> {
+    let mut grouped = vec![false; lorentzians.len()];
+    let mut units = Vec::new();
+    for multiplet in multiplets {
+        let valid = !multiplet.members.is_empty()
+            && multiplet.members.len() == multiplet.offsets.len()
+            && multiplet
+                .members
+                .iter()
+                .all(|member| *member < lorentzians.len() && !grouped[*member]);
+        if valid {
+            multiplet
+                .members
+                .iter()
+                .for_each(|member| grouped[*member] = true);
+            units.push((multiplet.members.clone(), multiplet.offsets.clone()));
+        }
+    }
+    units.extend(
+        (0..lorentzians.len())
+            .filter(|index| !grouped[*index])
+            .map(|index| (vec![index], vec![0.0])),
+    );
+
+    units
+        .into_iter()
+        .map(|(members, offsets)| {
+            let n = members.len() as f64;
+            let mut constraint = Constraint {
+                allow_negative: true,
+                fixed: true,
+                ..Default::default()
+            };
+            members
+                .iter()
+                .zip(offsets.iter())
+                .for_each(|(member, offset)| {
+                    let member = constraints.get(*member).copied().unwrap_or_default();
+                    constraint.position = intersect(
+                        constraint.position,
+                        member
+                            .position
+                            .map(|(lower, upper)| (lower - offset, upper - offset)),
+                    );
+                    constraint.half_width = intersect(constraint.half_width, member.half_width);
+                    constraint.allow_negative &= member.allow_negative;
+                    constraint.fixed &= member.fixed;
+                });
+            let centre = members
+                .iter()
+                .zip(offsets.iter())
+                .map(|(member, offset)| lorentzians[*member].maxp() - offset)
+                .sum::<f64>()
+                / n;
+            let hw = members
+                .iter()
+                .map(|member| lorentzians[*member].hw())
+                .sum::<f64>()
+                / n;
+            let (min_hw, max_hw) = constraint.half_width.unwrap_or((0.0, f64::INFINITY));
+            let min_hw = min_hw.max(MIN_HALF_WIDTH * spacing);
+            if max_hw.is_nan() || max_hw < min_hw {
+                return Err(format!(
+                    "the maximum half width ({} ppm) is below the minimum ({} ppm), which is at \
+                     least {} times the spacing of the data points",
+                    max_hw, min_hw, MIN_HALF_WIDTH
+                ));
+            }
+            constraint.half_width = Some((min_hw, max_hw));
+            let hw = hw.clamp(min_hw, max_hw);
+            let centre = match constraint.position {
+                Some((lower, upper)) if lower <= upper => centre.clamp(lower, upper),
+                _ => centre,
+            };
+            let mut parameters = vec![centre, hw];
+            parameters.extend(members.iter().map(|member| {
+                let sf = lorentzians[*member].sf();
+                match constraint.allow_negative || sf > 0.0 {
+                    true => sf,
+                    false => f64::EPSILON,
+                }
+            }));
+            Ok(Unit {
+                members,
+                offsets,
+                constraint,
+                parameters,
+            })
+        })
+        .collect()
+}
+
+/// Refines the Lorentzians against the intensities `y` at the sorted points
+/// `x` where `mask` is set. Each sweep refits every peak, or multiplet, in
+/// turn to the residual of all other peaks within a window around it. Peaks
+/// contribute to the residual within [`SUPPORT`] half widths, so a sweep costs
+/// time proportional to the number of peaks rather than to the number of
+/// peaks times the number of points. The order of the Lorentzians is
+/// preserved. `constraints` is either empty or holds one constraint per
+/// Lorentzian. Fails if the half width bounds cannot be met.
+pub(crate) fn refine(
+    lorentzians: &[Lorentzian],
+    constraints: &[Constraint],
+    multiplets: &[Multiplet],
+    x: &[f64],
+    y: &[f64],
+    mask: &[bool],
+    sweeps: usize,
+) -> std::result::Result<Vec<Lorentzian>, String> {
+    let spacing = match x.len() {
+        0 | 1 => 1.0,
+        len => (x[len - 1] - x[0]).abs() / (len - 1) as f64,
+    };
+    let mut units = units(lorentzians, constraints, multiplets, spacing)?;
+    let mut model = vec![0.0; x.len()];
+    units
+        .iter()
+        .for_each(|unit| Unit::add_to(&unit.parameters, &unit.offsets, x, &mut model, 1.0));
+    for _ in 0..sweeps {
+        for unit in units.iter_mut().filter(|unit| !unit.constraint.fixed) {
+            let (centre, hw) = (unit.parameters[0], unit.parameters[1]);
+            let reach = WINDOW * hw.max(spacing);
+            let window = Unit::range(&unit.parameters, &unit.offsets, x, reach)
+                .filter(|i| mask[*i])
+                .collect::<Vec<usize>>();
+            if window.len() < unit.parameters.len() + 1 {
+                continue;
+            }
+            let window_x = window.iter().map(|i| x[*i]).collect::<Vec<f64>>();
+            let target = window
+                .iter()
+                .map(|i| y[*i] - model[*i] + Unit::evaluate(&unit.parameters, &unit.offsets, x[*i]))
+                .collect::<Vec<f64>>();
+            let position = unit
+                .constraint
+                .position
+                .unwrap_or((centre - 0.5 * reach, centre + 0.5 * reach));
+            let half_width = unit.constraint.half_width.unwrap_or((0.0, f64::INFINITY));
+            let refined = fit_unit(
+                unit.parameters.clone(),
+                &unit.offsets,
+                &window_x,
+                &target,
+                position,
+                half_width,
+                unit.constraint.allow_negative,
+            );
+            Unit::add_to(&unit.parameters, &unit.offsets, x, &mut model, -1.0);
+            Unit::add_to(&refined, &unit.offsets, x, &mut model, 1.0);
+            unit.parameters = refined;
+        }
+    }
+    let mut refined = lorentzians.to_vec();
+    units
+        .iter()
+        .filter(|unit| !unit.constraint.fixed)
+        .flat_map(Unit::lorentzians)
+        .for_each(|(member, lorentzian)| {
+            refined[member] = lorentzian;
+        });
+
+    Ok(refined)
+}
+
+/// Mean squared error of the superposition of the Lorentzians at the points
+/// where `mask` is set.
+pub(crate) fn mse(lorentzians: &[Lorentzian], x: &[f64], y: &[f64], mask: &[bool]) -> f64 {
+    let (sum, count) = x
+        .iter()
+        .zip(y.iter())
+        .zip(mask.iter())
+        .filter(|(_, mask)| **mask)
+        .fold((0.0, 0usize), |(sum, count), ((x, y), _)| {
+            let fit = Lorentzian::superposition(*x, lorentzians);
+            (sum + (y - fit).powi(2), count + 1)
+        });
+
+    match count {
+        0 => 0.0,
+        count => sum / count as f64,
+    }
+}
+
+/// Combines the peaks of an earlier deconvolution with newly detected ones.
+/// Earlier peaks with a detected peak within their half width are kept, in
+/// their original order, detected peaks without such a match are appended and
+/// earlier peaks that were not detected again are dropped.
+pub(crate) fn match_peaks(initial: &[Lorentzian], detected: &[Lorentzian]) -> Vec<Lorentzian> {
+    let mut used = vec![false; detected.len()];
+    let mut peaks = initial
+        .iter()
+        .filter(|peak| {
+            let nearest = detected
+                .iter()
+                .enumerate()
+                .filter(|(index, candidate)| {
+                    !used[*index]
+                        && (candidate.maxp() - peak.maxp()).abs() <= peak.hw().max(candidate.hw())
+                })
+                .min_by(|a, b| {
+                    (a.1.maxp() - peak.maxp())
+                        .abs()
+                        .total_cmp(&(b.1.maxp() - peak.maxp()).abs())
+                })
+                .map(|(index, _)| index);
+            match nearest {
+                Some(index) => {
+                    used[index] = true;
+                    true
+                }
+                None => false,
+            }
+        })
+        .copied()
+        .collect::<Vec<Lorentzian>>();
+    peaks.extend(
+        detected
+            .iter()
+            .zip(used.iter())
+            .filter(|(_, used)| !**used)
+            .map(|(peak, _)| *peak),
+    );
+
+    peaks
+}
+
+/// Multiplet expected at `centre` in ppm with `lines` lines spaced by the
+/// coupling constant `coupling` in Hz.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct MultipletPattern {
+    pub(crate) centre: f64,
+    pub(crate) lines: usize,
+    pub(crate) coupling: f64,
+}
+
+/// Parameter bounds and multiplet patterns enforced when fitting.
+#[derive(Clone, Debug)]
+pub(crate) struct Bounds {
+    /// Range of the half width at half maximum in Hz.
+    pub(crate) half_width: Option<(f64, f64)>,
+    /// Maximum distance in Hz a peak may move from its initial position.
+    pub(crate) max_drift: Option<f64>,
+    /// Whether peaks may have a negative scale factor, the default. If not,
+    /// peaks that come out of the deconvolution with a negative scale factor
+    /// are refit, see [`Bounds::violations`].
+    pub(crate) allow_negative: bool,
+    pub(crate) multiplets: Vec<MultipletPattern>,
+}
+
+impl Default for Bounds {
+    fn default() -> Self {
+        Self {
+            half_width: None,
+            max_drift: None,
+            allow_negative: true,
+            multiplets: Vec::new(),
+        }
+    }
+}
+
+impl Bounds {
+    /// Whether every peak must be refined to enforce the bounds. The sign of
+    /// the scale factors does not count, as it is enforced by refitting only
+    /// the peaks that violate it.
+    pub(crate) fn is_active(&self) -> bool {
+        self.half_width.is_some() || self.max_drift.is_some() || !self.multiplets.is_empty()
+    }
+
+    /// Constraints that refit only the peaks with a negative scale factor,
+    /// or `None` if negative scale factors are allowed or there are none.
+    pub(crate) fn violations(&self, peaks: &[Lorentzian]) -> Option<Vec<Constraint>> {
+        let violated = !self.allow_negative && peaks.iter().any(|peak| peak.sf() < 0.0);
+
+        violated.then(|| {
+            peaks
+                .iter()
+                .map(|peak| Constraint {
+                    fixed: peak.sf() >= 0.0,
+                    ..Default::default()
+                })
+                .collect()
+        })
+    }
+
+    /// Constraint of a peak at the given spectrometer frequency in MHz.
+    pub(crate) fn constraint(&self, peak: &Lorentzian, frequency: f64) -> Constraint {
+        Constraint {
+            position: self.max_drift.map(|drift| {
+                let drift = drift / frequency;
+                (peak.maxp() - drift, peak.maxp() + drift)
+            }),
+            half_width: self
+                .half_width
+                .map(|(lower, upper)| (lower / frequency, upper / frequency)),
+            allow_negative: self.allow_negative,
+            fixed: false,
+        }
+    }
+
+    /// Assigns the peaks to the multiplet patterns. Each line of a pattern
+    /// takes the nearest unassigned peak within half the line spacing. Lines
+    /// without a peak are added, with the average scale factor and half width
+    /// of the assigned lines, as long as at least one line was found.
+    pub(crate) fn multiplets(&self, peaks: &mut Vec<Lorentzian>, frequency: f64) -> Vec<Multiplet> {
+        let mut assigned = vec![false; peaks.len()];
+        let mut multiplets = Vec::new();
+        for pattern in &self.multiplets {
+            let spacing = pattern.coupling / frequency;
+            let offsets = (0..pattern.lines)
+                .map(|k| (k as f64 - 0.5 * (pattern.lines - 1) as f64) * spacing)
+                .collect::<Vec<f64>>();
+            let tolerance = match pattern.lines {
+                1 => f64::INFINITY,
+                _ => 0.5 * spacing,
+            };
+            let members = offsets
+                .iter()
+                .map(|offset| {
+                    let expected = pattern.centre + offset;
+                    let nearest = peaks
+                        .iter()
+                        .enumerate()
+                        .filter(|(index, peak)| {
+                            !assigned[*index] && (peak.maxp() - expected).abs() <= tolerance
+                        })
+                        .min_by(|a, b| {
+                            (a.1.maxp() - expected)
+                                .abs()
+                                .total_cmp(&(b.1.maxp() - expected).abs())
+                        })
+                        .map(|(index, _)| index);
+                    if let Some(index) = nearest {
+                        assigned[index] = true;
+                    }
+                    nearest
+                })
+                .collect::<Vec<Option<usize>>>();
+            let found = members.iter().flatten().copied().collect::<Vec<usize>>();
+            if found.is_empty() {
+                continue;
+            }
+            let sf = found.iter().map(|index| peaks[*index].sf()).sum::<f64>() / found.len() as f64;
+            let hw = found.iter().map(|index| peaks[*index].hw()).sum::<f64>() / found.len() as f64;
+            let members = members
+                .into_iter()
+                .zip(offsets.iter())
+                .map(|(member, offset)| match member {
+                    Some(index) => index,
+                    None => {
+                        let position = pattern.centre + offset;
+                        peaks.push(Lorentzian::new(sf * hw, hw.powi(2), position));
+                        assigned.push(true);
+                        peaks.len() - 1
+                    }
+                })
+                .collect::<Vec<usize>>();
+            multiplets.push(Multiplet { members, offsets });
+        }
+
+        multiplets
+    }
+}
@@ -1,9 +1,25 @@
 use extendr_api::prelude::*;
 
+mod annotations;
+mod binning;
+mod boundaries;
 mod deconvoluter;
 mod deconvolution;
+mod fitting;
+mod library;
 mod lorentzian;
+mod multiplets;
+mod optimization;
+mod parameters;
+mod presets;
+mod profiles;
+mod provenance;
+mod quantification;
+mod quality;
+mod resampling;
 mod spectrum;
+mod targets;
+mod util;
 
 extendr_module! {
     mod mdrb;
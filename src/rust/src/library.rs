@@ -0,0 +1,687 @@
+use crate::multiplets::{self, Multiplet};
+use crate::util;
+use extendr_api::prelude::*;
+use metabodecon::deconvolution::Lorentzian;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+fn default_tolerance() -> f64 {
+    0.03
+}
+
+fn default_intensity() -> f64 {
+    1.0
+}
+
+/// Multiplet expected for a metabolite.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Pattern {
+    /// Expected chemical shift of the multiplet centre in ppm.
+    pub(crate) shift: f64,
+    /// Maximum deviation from the expected shift in ppm.
+    #[serde(default = "default_tolerance")]
+    pub(crate) tolerance: f64,
+    /// Expected multiplicity (`s`, `d`, `t`, `q` or `dd`), if known.
+    #[serde(default)]
+    pub(crate) multiplicity: Option<String>,
+    /// Expected (larger) coupling constant in Hz, if known.
+    #[serde(default)]
+    pub(crate) coupling: Option<f64>,
+    /// Smaller coupling constant of a doublet of doublets in Hz.
+    #[serde(default)]
+    pub(crate) second_coupling: Option<f64>,
+    /// Intensity relative to the other multiplets of the metabolite, usually
+    /// the number of protons.
+    #[serde(default = "default_intensity")]
+    pub(crate) intensity: f64,
+}
+
+impl Pattern {
+    /// Lines of the multiplet as offsets from its centre in Hz and fractions
+    /// of its intensity. Multiplets without the coupling constants they need
+    /// are treated as singlets.
+    fn lines(&self) -> Vec<(f64, f64)> {
+        let multiplicity = self.multiplicity.as_deref().map(str::to_lowercase);
+        match (multiplicity.as_deref(), self.coupling, self.second_coupling) {
+            (Some("d"), Some(j), _) => vec![(-0.5 * j, 0.5), (0.5 * j, 0.5)],
+            (Some("t"), Some(j), _) => vec![(-j, 0.25), (0.0, 0.5), (j, 0.25)],
+            (Some("q"), Some(j), _) => vec![
+                (-1.5 * j, 0.125),
+                (-0.5 * j, 0.375),
+                (0.5 * j, 0.375),
+                (1.5 * j, 0.125),
+            ],
+            (Some("dd"), Some(j), Some(k)) => vec![
+                (-0.5 * (j + k), 0.25),
+                (-0.5 * (j - k), 0.25),
+                (0.5 * (j - k), 0.25),
+                (0.5 * (j + k), 0.25),
+            ],
+            _ => vec![(0.0, 1.0)],
+        }
+    }
+}
+
+/// Metabolite of the reference library.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Metabolite {
+    pub(crate) name: String,
+    pub(crate) patterns: Vec<Pattern>,
+}
+
+/// Reads a library from a JSON or CSV file, chosen by the file extension.
+///
+/// JSON libraries are arrays of `{"name": , "patterns": [{"shift": , ...}]}`
+/// objects. CSV libraries have a header and one row per multiplet with the
+/// columns `metabolite` and `shift` and the optional columns `tolerance`,
+/// `multiplicity`, `coupling`, `second_coupling` and `intensity`. Rows of the same metabolite
+/// are collected in the order of their first appearance.
+pub(crate) fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Metabolite>> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .map_err(|error| Error::from(format!("{}: {}", path.display(), error)))?;
+    let is_csv = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let metabolites = if is_csv {
+        read_csv(&content)
+    } else {
+        serde_json::from_str::<Vec<Metabolite>>(&content).map_err(|error| error.to_string())
+    }
+    .map_err(|error| Error::from(format!("{}: {}", path.display(), error)))?;
+    if let Some(metabolite) = metabolites.iter().find(|metabolite| {
+        metabolite.patterns.is_empty()
+            || metabolite.patterns.iter().any(|pattern| {
+                !(pattern.shift.is_finite() && pattern.tolerance > 0.0 && pattern.intensity > 0.0)
+            })
+    }) {
+        return Err(Error::from(format!(
+            "{}: metabolite {:?} must have patterns with a finite shift and a positive \
+             tolerance and intensity",
+            path.display(),
+            metabolite.name
+        )));
+    }
+
+    Ok(metabolites)
+}
+
+/// Splits a line of a CSV file into its fields. Fields may be enclosed in
+/// double quotes to contain commas, with `""` standing for a quote.
+/// Whitespace around the fields is removed.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (',', false) => fields.push(std::mem::take(&mut field).trim().to_string()),
+            (c, _) => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+
+    fields
+}
+
+fn read_csv(content: &str) -> std::result::Result<Vec<Metabolite>, String> {
+    let mut rows = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(split_csv_line);
+    let header = rows.next().ok_or("library is empty")?;
+    let column = |name: &str| header.iter().position(|column| column == name);
+    let metabolite = column("metabolite").ok_or("library must have a metabolite column")?;
+    let shift = column("shift").ok_or("library must have a shift column")?;
+    let tolerance = column("tolerance");
+    let multiplicity = column("multiplicity");
+    let coupling = column("coupling");
+    let second_coupling = column("second_coupling");
+    let intensity = column("intensity");
+    let mut metabolites = Vec::<Metabolite>::new();
+    for (line, row) in rows.enumerate() {
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|column| row.get(column))
+                .filter(|field| !field.is_empty() && field.as_str() != "NA")
+        };
+        let number = |column: Option<usize>| {
+            field(column)
+                .map(|field| {
+                    field
+                        .parse::<f64>()
+                        .map_err(|_| format!("row {}: {:?} is not a number", line + 1, field))
+                })
+                .transpose()
+        };
+        let name = field(Some(metabolite))
+            .ok_or_else(|| format!("row {}: metabolite is missing", line + 1))?;
+        let pattern = Pattern {
+            shift: number(Some(shift))?
+                .ok_or_else(|| format!("row {}: shift is missing", line + 1))?,
+            tolerance: number(tolerance)?.unwrap_or_else(default_tolerance),
+            multiplicity: field(multiplicity).cloned(),
+            coupling: number(coupling)?,
+            second_coupling: number(second_coupling)?,
+            intensity: number(intensity)?.unwrap_or_else(default_intensity),
+        };
+        match metabolites.iter_mut().find(|known| known.name == *name) {
+            Some(known) => known.patterns.push(pattern),
+            None => metabolites.push(Metabolite {
+                name: name.clone(),
+                patterns: vec![pattern],
+            }),
+        }
+    }
+
+    Ok(metabolites)
+}
+
+/// Multiplet assigned to a pattern of a metabolite.
+#[derive(Copy, Clone, Debug)]
+struct Assignment {
+    metabolite: usize,
+    pattern: usize,
+    multiplet: usize,
+    score: f64,
+}
+
+/// Score in `[0, 1]` of a multiplet as the given pattern. The shift
+/// deviation decreases the score linearly up to the tolerance, a different
+/// multiplicity halves it and a deviating coupling constant decreases it
+/// like a Gaussian with the coupling tolerance as its standard deviation.
+fn score(
+    pattern: &Pattern,
+    multiplet: &Multiplet,
+    centre: f64,
+    tolerances: &multiplets::Tolerances,
+) -> f64 {
+    let deviation = (centre - pattern.shift).abs();
+    if deviation > pattern.tolerance {
+        return 0.0;
+    }
+    let mut score = 1.0 - deviation / pattern.tolerance;
+    if let Some(multiplicity) = &pattern.multiplicity {
+        if !multiplicity.eq_ignore_ascii_case(multiplet.kind.label()) {
+            score *= 0.5;
+        }
+    }
+    if let (Some(expected), Some(coupling)) = (pattern.coupling, multiplet.couplings.first()) {
+        let deviation = (coupling - expected) / tolerances.coupling_tolerance;
+        score *= (-0.5 * deviation.powi(2)).exp();
+    }
+
+    score
+}
+
+/// Assigns the multiplets to the patterns of the metabolites. Candidate
+/// assignments are taken in the order of decreasing score, such that each
+/// multiplet and each pattern is assigned at most once.
+fn assign(
+    metabolites: &[Metabolite],
+    multiplets: &[Multiplet],
+    lorentzians: &[Lorentzian],
+    tolerances: &multiplets::Tolerances,
+) -> Vec<Assignment> {
+    let centres = multiplets
+        .iter()
+        .map(|multiplet| multiplet.centre(lorentzians))
+        .collect::<Vec<f64>>();
+    let mut candidates = Vec::new();
+    for (m, metabolite) in metabolites.iter().enumerate() {
+        for (p, pattern) in metabolite.patterns.iter().enumerate() {
+            for (k, multiplet) in multiplets.iter().enumerate() {
+                let score = score(pattern, multiplet, centres[k], tolerances);
+                if score > 0.0 {
+                    candidates.push(Assignment {
+                        metabolite: m,
+                        pattern: p,
+                        multiplet: k,
+                        score,
+                    });
+                }
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let mut assignments = Vec::<Assignment>::new();
+    for candidate in candidates {
+        let taken = assignments.iter().any(|assignment| {
+            assignment.multiplet == candidate.multiplet
+                || (assignment.metabolite == candidate.metabolite
+                    && assignment.pattern == candidate.pattern)
+        });
+        if !taken {
+            assignments.push(candidate);
+        }
+    }
+
+    assignments
+}
+
+/// Identifies the metabolites of the library in a deconvolution, based on
+/// its multiplets. The score of a metabolite is the mean score of its
+/// patterns, where unmatched patterns score 0, multiplied by the ratio of
+/// the smallest to the largest area per unit of relative intensity of the
+/// matched patterns.
+pub(crate) fn identify(
+    metabolites: &[Metabolite],
+    lorentzians: &[Lorentzian],
+    frequency: f64,
+    tolerances: &multiplets::Tolerances,
+) -> Result<List> {
+    let multiplets = multiplets::group(lorentzians, frequency, tolerances);
+    let assignments = assign(metabolites, &multiplets, lorentzians, tolerances);
+
+    let len = metabolites.len();
+    let mut score = Vec::<f64>::with_capacity(len);
+    let mut matched = Vec::<i32>::with_capacity(len);
+    let mut patterns = Vec::<i32>::with_capacity(len);
+    let mut area = Vec::<f64>::with_capacity(len);
+    metabolites.iter().enumerate().for_each(|(m, metabolite)| {
+        let own = assignments
+            .iter()
+            .filter(|assignment| assignment.metabolite == m)
+            .collect::<Vec<&Assignment>>();
+        let scaled = own
+            .iter()
+            .map(|assignment| {
+                multiplets[assignment.multiplet].area(lorentzians)
+                    / metabolite.patterns[assignment.pattern].intensity
+            })
+            .collect::<Vec<f64>>();
+        let consistency = match scaled.len() {
+            0 | 1 => 1.0,
+            _ => {
+                let min = scaled.iter().copied().fold(f64::INFINITY, f64::min);
+                let max = scaled.iter().copied().fold(0.0, f64::max);
+                if max > 0.0 {
+                    min / max
+                } else {
+                    0.0
+                }
+            }
+        };
+        let total = own.iter().map(|assignment| assignment.score).sum::<f64>();
+        score.push(consistency * total / metabolite.patterns.len() as f64);
+        matched.push(own.len() as i32);
+        patterns.push(metabolite.patterns.len() as i32);
+        area.push(
+            own.iter()
+                .map(|assignment| multiplets[assignment.multiplet].area(lorentzians))
+                .sum(),
+        );
+    });
+    let names = metabolites
+        .iter()
+        .map(|metabolite| metabolite.name.clone())
+        .collect::<Vec<String>>();
+    let columns: Vec<(&str, Robj)> = vec![
+        ("metabolite", names.into()),
+        ("score", score.into()),
+        ("matched", matched.into()),
+        ("patterns", patterns.into()),
+        ("area", area.into()),
+    ];
+    let metabolite_table = util::data_frame(columns, len)?;
+
+    let len = assignments.len();
+    let mut metabolite = Vec::<String>::with_capacity(len);
+    let mut shift = Vec::<f64>::with_capacity(len);
+    let mut multiplet = Vec::<i32>::with_capacity(len);
+    let mut kind = Vec::<String>::with_capacity(len);
+    let mut centre_ppm = Vec::<f64>::with_capacity(len);
+    let mut assignment_score = Vec::<f64>::with_capacity(len);
+    let mut assignment_area = Vec::<f64>::with_capacity(len);
+    let mut per_lorentzian = vec![None::<String>; lorentzians.len()];
+    assignments.iter().for_each(|assignment| {
+        let name = &metabolites[assignment.metabolite].name;
+        let assigned = &multiplets[assignment.multiplet];
+        metabolite.push(name.clone());
+        shift.push(metabolites[assignment.metabolite].patterns[assignment.pattern].shift);
+        multiplet.push(assignment.multiplet as i32 + 1);
+        kind.push(assigned.kind.label().to_string());
+        centre_ppm.push(assigned.centre(lorentzians));
+        assignment_score.push(assignment.score);
+        assignment_area.push(assigned.area(lorentzians));
+        assigned
+            .members
+            .iter()
+            .for_each(|member| per_lorentzian[*member] = Some(name.clone()));
+    });
+    let columns: Vec<(&str, Robj)> = vec![
+        ("metabolite", metabolite.into()),
+        ("shift", shift.into()),
+        ("multiplet", multiplet.into()),
+        ("type", kind.into()),
+        ("centre_ppm", centre_ppm.into()),
+        ("score", assignment_score.into()),
+        ("area", assignment_area.into()),
+    ];
+    let assignment_table = util::data_frame(columns, len)?;
+
+    let mut result = HashMap::<&str, Robj>::new();
+    result.insert("metabolites", metabolite_table);
+    result.insert("assignments", assignment_table);
+    result.insert("lorentzians", per_lorentzian.into());
+
+    List::from_hashmap(result)
+}
+
+/// Settings of the library fit.
+#[derive(Clone, Debug)]
+pub(crate) struct FitSettings {
+    /// Maximum shift of each metabolite from its library positions in ppm.
+    pub(crate) max_shift: f64,
+    /// Full width at half maximum of the simulated lines in Hz.
+    pub(crate) line_width: f64,
+    /// Maximum factor by which the lines of a metabolite may be broadened or
+    /// narrowed.
+    pub(crate) max_broadening: f64,
+    /// Number of sweeps over all metabolites.
+    pub(crate) sweeps: usize,
+}
+
+impl Default for FitSettings {
+    fn default() -> Self {
+        Self {
+            max_shift: 0.01,
+            line_width: 1.0,
+            max_broadening: 2.0,
+            sweeps: 5,
+        }
+    }
+}
+
+impl TryFrom<List> for FitSettings {
+    type Error = Error;
+
+    fn try_from(value: List) -> Result<Self> {
+        let mut settings = Self::default();
+        for (name, value) in value.iter() {
+            let number = || {
+                value
+                    .as_real()
+                    .filter(|value| value.is_finite() && *value >= 0.0)
+                    .ok_or_else(|| Error::from(format!("{} must be a non-negative number", name)))
+            };
+            match name {
+                "max_shift" => settings.max_shift = number()?,
+                "line_width" => settings.line_width = number()?,
+                "max_broadening" => settings.max_broadening = number()?,
+                "sweeps" => settings.sweeps = number()? as usize,
+                _ => return Err(Error::from(format!("unknown setting {:?}", name))),
+            }
+        }
+        if settings.line_width <= 0.0 || settings.max_broadening < 1.0 {
+            return Err(Error::from(
+                "line_width must be positive and max_broadening at least 1",
+            ));
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Steps of the grid searched for the shift and the broadening of each
+/// metabolite.
+const SHIFT_STEPS: usize = 21;
+const BROADENING_STEPS: usize = 9;
+
+/// Fitted parameters of a metabolite. The amplitude is the area per unit of
+/// relative intensity of its patterns.
+#[derive(Copy, Clone, Debug, Default)]
+struct Component {
+    amplitude: f64,
+    shift: f64,
+    broadening: f64,
+}
+
+/// Lorentzians of a metabolite with unit amplitude, shifted by `shift` ppm
+/// and with lines of half width `hw` ppm.
+fn simulate(metabolite: &Metabolite, shift: f64, hw: f64, frequency: f64) -> Vec<Lorentzian> {
+    metabolite
+        .patterns
+        .iter()
+        .flat_map(|pattern| {
+            pattern.lines().into_iter().map(move |(offset, fraction)| {
+                let sf = pattern.intensity * fraction / std::f64::consts::PI;
+                Lorentzian::new(
+                    sf * hw,
+                    hw.powi(2),
+                    pattern.shift + shift + offset / frequency,
+                )
+            })
+        })
+        .collect()
+}
+
+fn grid(lower: f64, upper: f64, steps: usize, geometric: bool) -> Vec<f64> {
+    (0..steps)
+        .map(|step| {
+            let t = step as f64 / (steps - 1) as f64;
+            if geometric {
+                lower * (upper / lower).powf(t)
+            } else {
+                lower + t * (upper - lower)
+            }
+        })
+        .collect()
+}
+
+/// Fits the spectrum as a non-negative linear combination of the simulated
+/// metabolite spectra, with a shift and a broadening per metabolite chosen
+/// from a grid. Only the data points within the mask and close to a line of
+/// the library are fitted. The metabolites are updated one at a time, with
+/// the amplitude given by the least squares solution against the residual
+/// of the others. Concentrations are relative to the reference metabolite,
+/// if given.
+pub(crate) fn fit(
+    metabolites: &[Metabolite],
+    reference: Option<usize>,
+    x: &[f64],
+    y: &[f64],
+    mask: &[bool],
+    frequency: f64,
+    settings: &FitSettings,
+) -> Result<List> {
+    let hw = 0.5 * settings.line_width / frequency;
+    let reach = settings.max_shift + 10.0 * settings.max_broadening * hw;
+    let lines = metabolites
+        .iter()
+        .flat_map(|metabolite| simulate(metabolite, 0.0, hw, frequency))
+        .map(|lorentzian| lorentzian.maxp())
+        .collect::<Vec<f64>>();
+    let indices = (0..x.len())
+        .filter(|i| mask[*i] && lines.iter().any(|line| (x[*i] - line).abs() <= reach))
+        .collect::<Vec<usize>>();
+    if indices.is_empty() {
+        return Err(Error::from("no data points close to the library lines"));
+    }
+    let fit_x = indices.iter().map(|i| x[*i]).collect::<Vec<f64>>();
+    let fit_y = indices.iter().map(|i| y[*i]).collect::<Vec<f64>>();
+    let shifts = grid(-settings.max_shift, settings.max_shift, SHIFT_STEPS, false);
+    let broadenings = grid(
+        1.0 / settings.max_broadening,
+        settings.max_broadening,
+        BROADENING_STEPS,
+        true,
+    );
+
+    let basis = |metabolite: &Metabolite, component: &Component| {
+        let lorentzians = simulate(
+            metabolite,
+            component.shift,
+            component.broadening * hw,
+            frequency,
+        );
+        Lorentzian::superposition_vec(&fit_x, &lorentzians)
+    };
+    let mut components = vec![
+        Component {
+            broadening: 1.0,
+            ..Default::default()
+        };
+        metabolites.len()
+    ];
+    let mut model = vec![0.0; fit_x.len()];
+    for _ in 0..settings.sweeps.max(1) {
+        for (metabolite, component) in metabolites.iter().zip(components.iter_mut()) {
+            let current = basis(metabolite, component);
+            let others = model
+                .iter()
+                .zip(current.iter())
+                .map(|(model, current)| model - component.amplitude * current)
+                .collect::<Vec<f64>>();
+            let residual = fit_y
+                .iter()
+                .zip(others.iter())
+                .map(|(y, others)| y - others)
+                .collect::<Vec<f64>>();
+            let unused = Component {
+                amplitude: 0.0,
+                ..*component
+            };
+            let mut best = (0.0, unused, vec![0.0; fit_x.len()]);
+            for shift in shifts.iter() {
+                for broadening in broadenings.iter() {
+                    let mut candidate = Component {
+                        amplitude: 0.0,
+                        shift: *shift,
+                        broadening: *broadening,
+                    };
+                    let values = basis(metabolite, &candidate);
+                    let product = residual
+                        .iter()
+                        .zip(values.iter())
+                        .map(|(residual, value)| residual * value)
+                        .sum::<f64>();
+                    let norm = values.iter().map(|value| value * value).sum::<f64>();
+                    if norm <= 0.0 {
+                        continue;
+                    }
+                    candidate.amplitude = (product / norm).max(0.0);
+                    let gain = candidate.amplitude * product;
+                    if gain > best.0 {
+                        best = (gain, candidate, values);
+                    }
+                }
+            }
+            let (_, best, values) = best;
+            model = others
+                .iter()
+                .zip(values.iter())
+                .map(|(others, value)| others + best.amplitude * value)
+                .collect();
+            *component = best;
+        }
+    }
+    let mse = fit_y
+        .iter()
+        .zip(model.iter())
+        .map(|(y, model)| (y - model).powi(2))
+        .sum::<f64>()
+        / fit_y.len() as f64;
+
+    let lorentzians = metabolites
+        .iter()
+        .zip(components.iter())
+        .flat_map(|(metabolite, component)| {
+            simulate(
+                metabolite,
+                component.shift,
+                component.broadening * hw,
+                frequency,
+            )
+            .into_iter()
+            .map(move |lorentzian| {
+                Lorentzian::new(
+                    component.amplitude * lorentzian.sfhw(),
+                    lorentzian.hw2(),
+                    lorentzian.maxp(),
+                )
+            })
+        })
+        .collect::<Vec<Lorentzian>>();
+    let fitted = Lorentzian::superposition_vec(x, &lorentzians);
+    let residuals = y
+        .iter()
+        .zip(fitted.iter())
+        .map(|(y, fitted)| y - fitted)
+        .collect::<Vec<f64>>();
+
+    let reference_amplitude = reference
+        .map(|reference| components[reference].amplitude)
+        .filter(|amplitude| *amplitude > 0.0);
+    let len = metabolites.len();
+    let names = metabolites
+        .iter()
+        .map(|metabolite| metabolite.name.clone())
+        .collect::<Vec<String>>();
+    let area = metabolites
+        .iter()
+        .zip(components.iter())
+        .map(|(metabolite, component)| {
+            component.amplitude
+                * metabolite
+                    .patterns
+                    .iter()
+                    .map(|pattern| pattern.intensity)
+                    .sum::<f64>()
+        })
+        .collect::<Vec<f64>>();
+    let concentration = components
+        .iter()
+        .map(|component| reference_amplitude.map(|reference| component.amplitude / reference))
+        .collect::<Vec<Option<f64>>>();
+    let columns: Vec<(&str, Robj)> = vec![
+        ("metabolite", names.into()),
+        (
+            "amplitude",
+            components
+                .iter()
+                .map(|component| component.amplitude)
+                .collect::<Vec<f64>>()
+                .into(),
+        ),
+        ("area", area.into()),
+        ("concentration", concentration.into()),
+        (
+            "shift",
+            components
+                .iter()
+                .map(|component| component.shift)
+                .collect::<Vec<f64>>()
+                .into(),
+        ),
+        (
+            "broadening",
+            components
+                .iter()
+                .map(|component| component.broadening)
+                .collect::<Vec<f64>>()
+                .into(),
+        ),
+    ];
+    let reference = reference.map(|reference| metabolites[reference].name.clone());
+
+    let mut result = HashMap::<&str, Robj>::new();
+    result.insert("metabolites", util::data_frame(columns, len)?);
+    result.insert("reference", Nullable::from(reference).into());
+    result.insert("fitted", fitted.into());
+    result.insert("residuals", residuals.into());
+    result.insert("mse", mse.into());
+
+    List::from_hashmap(result)
+}
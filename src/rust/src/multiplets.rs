@@ -0,0 +1,283 @@
+use crate::util;
+use extendr_api::prelude::*;
+use metabodecon::deconvolution::Lorentzian;
+use std::collections::HashMap;
+
+/// Tolerances used to decide whether neighbouring peaks form a multiplet.
+#[derive(Clone, Debug)]
+pub(crate) struct Tolerances {
+    /// Largest coupling constant considered, in Hz.
+    pub(crate) max_coupling: f64,
+    /// Maximum difference between spacings that are expected to be equal,
+    /// in Hz.
+    pub(crate) coupling_tolerance: f64,
+    /// Maximum relative deviation of the line areas from the expected ratios.
+    pub(crate) intensity_tolerance: f64,
+    /// Maximum relative deviation of the line widths from their mean.
+    pub(crate) width_tolerance: f64,
+}
+
+impl Default for Tolerances {
+    fn default() -> Self {
+        Self {
+            max_coupling: 20.0,
+            coupling_tolerance: 0.5,
+            intensity_tolerance: 0.3,
+            width_tolerance: 0.3,
+        }
+    }
+}
+
+impl TryFrom<List> for Tolerances {
+    type Error = Error;
+
+    fn try_from(value: List) -> Result<Self> {
+        let mut tolerances = Self::default();
+        for (name, value) in value.iter() {
+            let number = || {
+                value
+                    .as_real()
+                    .filter(|value| value.is_finite() && *value > 0.0)
+                    .ok_or_else(|| Error::from(format!("{} must be a positive number", name)))
+            };
+            match name {
+                "max_coupling" => tolerances.max_coupling = number()?,
+                "coupling_tolerance" => tolerances.coupling_tolerance = number()?,
+                "intensity_tolerance" => tolerances.intensity_tolerance = number()?,
+                "width_tolerance" => tolerances.width_tolerance = number()?,
+                _ => return Err(Error::from(format!("unknown tolerance {:?}", name))),
+            }
+        }
+
+        Ok(tolerances)
+    }
+}
+
+/// Recognised multiplet patterns.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Kind {
+    Singlet,
+    Doublet,
+    Triplet,
+    Quartet,
+    DoubletOfDoublets,
+}
+
+impl Kind {
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            Self::Singlet => "s",
+            Self::Doublet => "d",
+            Self::Triplet => "t",
+            Self::Quartet => "q",
+            Self::DoubletOfDoublets => "dd",
+        }
+    }
+
+    /// Expected area ratios of the lines.
+    fn ratios(&self) -> &'static [f64] {
+        match self {
+            Self::Singlet => &[1.0],
+            Self::Doublet => &[1.0, 1.0],
+            Self::Triplet => &[1.0, 2.0, 1.0],
+            Self::Quartet => &[1.0, 3.0, 3.0, 1.0],
+            Self::DoubletOfDoublets => &[1.0, 1.0, 1.0, 1.0],
+        }
+    }
+}
+
+/// Group of Lorentzians forming a multiplet. `members` are indices into the
+/// Lorentzians of the deconvolution, ordered by position.
+#[derive(Clone, Debug)]
+pub(crate) struct Multiplet {
+    pub(crate) kind: Kind,
+    pub(crate) members: Vec<usize>,
+    /// Coupling constants in Hz, the larger one first.
+    pub(crate) couplings: Vec<f64>,
+}
+
+impl Multiplet {
+    /// Summed area of the lines.
+    pub(crate) fn area(&self, lorentzians: &[Lorentzian]) -> f64 {
+        self.members
+            .iter()
+            .map(|member| std::f64::consts::PI * lorentzians[*member].sf())
+            .sum()
+    }
+
+    /// Area weighted mean position of the lines in ppm. If the areas sum to
+    /// zero, the unweighted mean is used.
+    pub(crate) fn centre(&self, lorentzians: &[Lorentzian]) -> f64 {
+        let lines = self.members.iter().map(|member| &lorentzians[*member]);
+        let total = lines.clone().map(|line| line.sf()).sum::<f64>();
+        if total == 0.0 {
+            return lines.map(|line| line.maxp()).sum::<f64>() / self.members.len() as f64;
+        }
+
+        lines.map(|line| line.maxp() * line.sf()).sum::<f64>() / total
+    }
+}
+
+/// Line of a deconvolution as used for the grouping.
+#[derive(Copy, Clone, Debug)]
+struct Line {
+    index: usize,
+    position: f64,
+    area: f64,
+    hw: f64,
+}
+
+fn is_similar(values: &[f64], expected: &[f64], tolerance: f64) -> bool {
+    let scale = values.iter().sum::<f64>() / expected.iter().sum::<f64>();
+    scale > 0.0
+        && values
+            .iter()
+            .zip(expected.iter())
+            .all(|(value, expected)| (value / (scale * expected) - 1.0).abs() <= tolerance)
+}
+
+/// Checks whether consecutive lines, as many as the pattern has, form the
+/// pattern and returns its coupling constants in Hz.
+fn matches(
+    kind: Kind,
+    lines: &[Line],
+    frequency: f64,
+    tolerances: &Tolerances,
+) -> Option<Vec<f64>> {
+    let spacings = lines
+        .windows(2)
+        .map(|pair| (pair[1].position - pair[0].position) * frequency)
+        .collect::<Vec<f64>>();
+    let areas = lines.iter().map(|line| line.area).collect::<Vec<f64>>();
+    let widths = lines.iter().map(|line| line.hw).collect::<Vec<f64>>();
+    let equal = |a: f64, b: f64| (a - b).abs() <= tolerances.coupling_tolerance;
+    if spacings
+        .iter()
+        .any(|spacing| *spacing > tolerances.max_coupling)
+        || !is_similar(
+            &widths,
+            &vec![1.0; widths.len()],
+            tolerances.width_tolerance,
+        )
+    {
+        return None;
+    }
+    if !is_similar(&areas, kind.ratios(), tolerances.intensity_tolerance) {
+        return None;
+    }
+    match kind {
+        Kind::Singlet => Some(Vec::new()),
+        Kind::Doublet => Some(vec![spacings[0]]),
+        Kind::Triplet | Kind::Quartet => {
+            let mean = spacings.iter().sum::<f64>() / spacings.len() as f64;
+            spacings
+                .iter()
+                .all(|spacing| equal(*spacing, mean))
+                .then(|| vec![mean])
+        }
+        Kind::DoubletOfDoublets => {
+            let small = 0.5 * (spacings[0] + spacings[2]);
+            let large = spacings[0] + spacings[1];
+            let distinct = !equal(spacings[1], small);
+            (equal(spacings[0], spacings[2]) && distinct).then(|| vec![large, small])
+        }
+    }
+}
+
+/// Groups the Lorentzians into multiplets. Scanning from the lowest chemical
+/// shift, each unassigned line is tried as the first line of a quartet, a
+/// doublet of doublets, a triplet and a doublet, in this order, using the
+/// following consecutive lines. Lines that fit no pattern are singlets.
+pub(crate) fn group(
+    lorentzians: &[Lorentzian],
+    frequency: f64,
+    tolerances: &Tolerances,
+) -> Vec<Multiplet> {
+    let mut lines = lorentzians
+        .iter()
+        .enumerate()
+        .map(|(index, lorentzian)| Line {
+            index,
+            position: lorentzian.maxp(),
+            area: std::f64::consts::PI * lorentzian.sf(),
+            hw: lorentzian.hw(),
+        })
+        .collect::<Vec<Line>>();
+    lines.sort_by(|a, b| a.position.total_cmp(&b.position));
+    let patterns = [
+        (Kind::Quartet, 4),
+        (Kind::DoubletOfDoublets, 4),
+        (Kind::Triplet, 3),
+        (Kind::Doublet, 2),
+    ];
+    let mut multiplets = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let (kind, size, couplings) = patterns
+            .iter()
+            .filter(|(_, size)| start + size <= lines.len())
+            .find_map(|(kind, size)| {
+                matches(*kind, &lines[start..start + size], frequency, tolerances)
+                    .map(|couplings| (*kind, *size, couplings))
+            })
+            .unwrap_or((Kind::Singlet, 1, Vec::new()));
+        multiplets.push(Multiplet {
+            kind,
+            members: lines[start..start + size]
+                .iter()
+                .map(|line| line.index)
+                .collect(),
+            couplings,
+        });
+        start += size;
+    }
+
+    multiplets
+}
+
+/// Table of the multiplets and the multiplet of each Lorentzian, in the order
+/// of the Lorentzians.
+pub(crate) fn to_list(
+    multiplets: &[Multiplet],
+    lorentzians: &[Lorentzian],
+    frequency: f64,
+) -> Result<List> {
+    let len = multiplets.len();
+    let mut kind = Vec::<String>::with_capacity(len);
+    let mut centre_ppm = Vec::<f64>::with_capacity(len);
+    let mut centre_hz = Vec::<f64>::with_capacity(len);
+    let mut j_hz = Vec::<Option<f64>>::with_capacity(len);
+    let mut j2_hz = Vec::<Option<f64>>::with_capacity(len);
+    let mut lines = Vec::<i32>::with_capacity(len);
+    let mut area = Vec::<f64>::with_capacity(len);
+    let mut assignment = vec![0; lorentzians.len()];
+    multiplets.iter().enumerate().for_each(|(id, multiplet)| {
+        let centre = multiplet.centre(lorentzians);
+        kind.push(multiplet.kind.label().to_string());
+        centre_ppm.push(centre);
+        centre_hz.push(centre * frequency);
+        j_hz.push(multiplet.couplings.first().copied());
+        j2_hz.push(multiplet.couplings.get(1).copied());
+        lines.push(multiplet.members.len() as i32);
+        area.push(multiplet.area(lorentzians));
+        multiplet
+            .members
+            .iter()
+            .for_each(|member| assignment[*member] = id as i32 + 1);
+    });
+    let columns: Vec<(&str, Robj)> = vec![
+        ("multiplet", (1..=len as i32).collect::<Vec<i32>>().into()),
+        ("type", kind.into()),
+        ("centre_ppm", centre_ppm.into()),
+        ("centre_hz", centre_hz.into()),
+        ("j_hz", j_hz.into()),
+        ("j2_hz", j2_hz.into()),
+        ("lines", lines.into()),
+        ("area", area.into()),
+    ];
+    let mut result = HashMap::<&str, Robj>::new();
+    result.insert("multiplets", util::data_frame(columns, len)?);
+    result.insert("assignment", assignment.into());
+
+    List::from_hashmap(result)
+}
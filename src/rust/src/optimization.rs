@@ -0,0 +1,524 @@
+use crate::spectrum::Spectrum;
+use crate::util;
+use extendr_api::prelude::*;
+use std::collections::HashMap;
+
+/// Values searched for each setting. Every combination is evaluated.
+#[derive(Clone, Debug)]
+pub(crate) struct SearchSpace {
+    pub(crate) smoothing_iterations: Vec<usize>,
+    pub(crate) smoothing_window_size: Vec<usize>,
+    pub(crate) noise_threshold: Vec<f64>,
+    pub(crate) fitting_iterations: Vec<usize>,
+}
+
+impl Default for SearchSpace {
+    fn default() -> Self {
+        Self {
+            smoothing_iterations: vec![1, 2, 3],
+            smoothing_window_size: vec![3, 5, 7],
+            noise_threshold: vec![4.0, 5.0, 6.4, 8.0],
+            fitting_iterations: vec![10],
+        }
+    }
+}
+
+impl TryFrom<List> for SearchSpace {
+    type Error = Error;
+
+    fn try_from(value: List) -> Result<Self> {
+        let mut search_space = Self::default();
+        for (name, value) in value.iter() {
+            let numbers = || match value.as_real_vector() {
+                Some(values) if !values.is_empty() && values.iter().all(|x| x.is_finite()) => {
+                    Ok(values)
+                }
+                _ => Err(Error::from(format!(
+                    "{} must be a non-empty numeric vector",
+                    name
+                ))),
+            };
+            let counts = || {
+                numbers()?
+                    .into_iter()
+                    .map(|x| match x >= 1.0 && x.fract() == 0.0 {
+                        true => Ok(x as usize),
+                        false => Err(Error::from(format!("{} must be positive integers", name))),
+                    })
+                    .collect::<Result<Vec<usize>>>()
+            };
+            match name {
+                "smoothing_iterations" => search_space.smoothing_iterations = counts()?,
+                "smoothing_window_size" => search_space.smoothing_window_size = counts()?,
+                "noise_threshold" => search_space.noise_threshold = numbers()?,
+                "fitting_iterations" => search_space.fitting_iterations = counts()?,
+                _ => return Err(Error::from(format!("unknown setting {:?}", name))),
+            }
+        }
+
+        Ok(search_space)
+    }
+}
+
+impl SearchSpace {
+    pub(crate) fn candidates(&self) -> Vec<Candidate> {
+        let mut candidates = Vec::new();
+        for smoothing_iterations in &self.smoothing_iterations {
+            for smoothing_window_size in &self.smoothing_window_size {
+                for noise_threshold in &self.noise_threshold {
+                    for fitting_iterations in &self.fitting_iterations {
+                        candidates.push(Candidate {
+                            smoothing_iterations: *smoothing_iterations,
+                            smoothing_window_size: *smoothing_window_size,
+                            noise_threshold: *noise_threshold,
+                            fitting_iterations: *fitting_iterations,
+                        });
+                    }
+                }
+            }
+        }
+
+        candidates
+    }
+}
+
+/// One combination of settings from the search space.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Candidate {
+    pub(crate) smoothing_iterations: usize,
+    pub(crate) smoothing_window_size: usize,
+    pub(crate) noise_threshold: f64,
+    pub(crate) fitting_iterations: usize,
+}
+
+/// Criterion minimised by the settings search.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Objective {
+    /// Mean squared error of the fit.
+    Mse,
+    /// MSE plus `penalty` per fitted peak.
+    PenalisedMse { penalty: f64 },
+    /// Akaike information criterion, `n ln(MSE) + 2k`.
+    Aic,
+    /// Bayesian information criterion, `n ln(MSE) + k ln(n)`.
+    Bic,
+}
+
+impl Objective {
+    pub(crate) fn new(name: &str, penalty: f64) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "mse" => Ok(Self::Mse),
+            "penalized_mse" | "penalised_mse" | "mse_penalty" => {
+                if !(penalty.is_finite() && penalty >= 0.0) {
+                    return Err(Error::from("penalty must be a non-negative number"));
+                }
+                Ok(Self::PenalisedMse { penalty })
+            }
+            "aic" => Ok(Self::Aic),
+            "bic" => Ok(Self::Bic),
+            _ => Err(Error::from(format!(
+                "unknown objective {:?}, expected \"mse\", \"penalized_mse\", \"aic\" or \"bic\"",
+                name
+            ))),
+        }
+    }
+
+    /// Score of a fit with `peaks` Lorentzians (three parameters each) to
+    /// `points` data points. Lower is better.
+    pub(crate) fn score(&self, mse: f64, peaks: usize, points: usize) -> f64 {
+        let n = points as f64;
+        let k = 3.0 * peaks as f64;
+        match self {
+            Self::Mse => mse,
+            Self::PenalisedMse { penalty } => mse + penalty * peaks as f64,
+            Self::Aic => n * mse.max(f64::MIN_POSITIVE).ln() + 2.0 * k,
+            Self::Bic => n * mse.max(f64::MIN_POSITIVE).ln() + k * n.ln(),
+        }
+    }
+}
+
+/// Number of data points within the signal regions of a spectrum.
+pub(crate) fn fitted_points(spectrum: &Spectrum) -> usize {
+    let boundaries = [spectrum.as_ref().signal_boundaries()];
+    let regions = spectrum.disjoint_signal_regions().unwrap_or(&boundaries);
+
+    spectrum
+        .as_ref()
+        .chemical_shifts()
+        .iter()
+        .filter(|x| {
+            regions
+                .iter()
+                .any(|(start, end)| **x >= start.min(*end) && **x <= start.max(*end))
+        })
+        .count()
+}
+
+/// How the scores of several reference spectra are combined.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Aggregate {
+    Mean,
+    Median,
+    /// The highest, i.e. worst, score.
+    Worst,
+}
+
+impl std::str::FromStr for Aggregate {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "mean" => Ok(Self::Mean),
+            "median" => Ok(Self::Median),
+            "worst" | "max" => Ok(Self::Worst),
+            _ => Err(Error::from(format!(
+                "unknown aggregate {:?}, expected \"mean\", \"median\" or \"worst\"",
+                value
+            ))),
+        }
+    }
+}
+
+impl Aggregate {
+    fn apply(&self, mut values: Vec<f64>) -> f64 {
+        match self {
+            Self::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            Self::Median => util::median(&mut values).unwrap_or(f64::NAN),
+            Self::Worst => values.into_iter().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+/// Configuration of a settings search over one or more reference spectra.
+#[derive(Clone, Debug)]
+pub(crate) struct Search {
+    pub(crate) candidates: Vec<Candidate>,
+    pub(crate) objective: Objective,
+    pub(crate) aggregate: Aggregate,
+    /// Number of folds of the cross-validation, if requested. Spectrum `i`
+    /// (0-based) belongs to fold `i % folds`.
+    pub(crate) folds: Option<usize>,
+}
+
+impl Search {
+    /// Search over `spectra` reference spectra, cross-validated with `folds`
+    /// folds if given. As many folds as spectra is leave-one-out.
+    pub(crate) fn new(
+        search_space: Nullable<List>,
+        objective: &str,
+        penalty: f64,
+        aggregate: &str,
+        folds: Nullable<f64>,
+        spectra: usize,
+    ) -> Result<Self> {
+        let search_space = match search_space {
+            NotNull(search_space) => SearchSpace::try_from(search_space)?,
+            Null => SearchSpace::default(),
+        };
+        let folds = match folds {
+            NotNull(folds) => {
+                if !(folds >= 2.0 && folds.fract() == 0.0 && folds as usize <= spectra) {
+                    return Err(Error::from(format!(
+                        "folds must be an integer from 2 to the number of spectra ({})",
+                        spectra
+                    )));
+                }
+                Some(folds as usize)
+            }
+            Null => None,
+        };
+
+        Ok(Self {
+            candidates: search_space.candidates(),
+            objective: Objective::new(objective, penalty)?,
+            aggregate: aggregate.parse::<Aggregate>()?,
+            folds,
+        })
+    }
+
+    /// Combines the evaluations of a candidate on the reference spectra for
+    /// which `include` holds. The score is aggregated over these spectra, the
+    /// MSE and number of peaks are averaged over them.
+    pub(crate) fn combine(
+        &self,
+        candidate: Candidate,
+        evaluations: &[Evaluation],
+        include: impl Fn(usize) -> bool,
+    ) -> Evaluation {
+        let select = || {
+            evaluations
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| include(*index))
+        };
+        if let Some((index, evaluation)) =
+            select().find(|(_, evaluation)| evaluation.error.is_some())
+        {
+            let error = evaluation.error.as_deref().unwrap_or_default();
+            return Evaluation::failed(candidate, format!("spectrum {}: {}", index + 1, error));
+        }
+        let count = select().count() as f64;
+        let mse = select()
+            .filter_map(|(_, evaluation)| evaluation.mse)
+            .sum::<f64>()
+            / count;
+        let mean_peaks = select()
+            .filter_map(|(_, evaluation)| evaluation.mean_peaks)
+            .sum::<f64>()
+            / count;
+        let scores = select()
+            .filter_map(|(_, evaluation)| evaluation.score)
+            .collect::<Vec<f64>>();
+
+        Evaluation {
+            candidate,
+            mse: Some(mse),
+            mean_peaks: Some(mean_peaks),
+            score: Some(self.aggregate.apply(scores)),
+            error: None,
+        }
+    }
+
+    /// Cross-validates the search, given the evaluations of each candidate
+    /// (outer) on each reference spectrum (inner). For every fold the best
+    /// candidate is selected on the other folds and scored on the fold.
+    pub(crate) fn cross_validate(&self, evaluations: &[Vec<Evaluation>]) -> Option<Vec<Fold>> {
+        let folds = self.folds?;
+        let folds = (0..folds)
+            .map(|fold| {
+                let training = evaluations
+                    .iter()
+                    .zip(self.candidates.iter())
+                    .map(|(evaluations, candidate)| {
+                        self.combine(*candidate, evaluations, |index| index % folds != fold)
+                    })
+                    .collect::<Vec<Evaluation>>();
+                let selected = Evaluation::best_index(&training);
+                let validation = selected.map(|selected| {
+                    self.combine(self.candidates[selected], &evaluations[selected], |index| {
+                        index % folds == fold
+                    })
+                });
+                Fold {
+                    training: selected.map(|selected| training[selected].clone()),
+                    validation,
+                }
+            })
+            .collect();
+
+        Some(folds)
+    }
+}
+
+/// Candidate selected on the training spectra of a fold together with its
+/// evaluation on the training and on the held out spectra.
+#[derive(Clone, Debug)]
+pub(crate) struct Fold {
+    pub(crate) training: Option<Evaluation>,
+    pub(crate) validation: Option<Evaluation>,
+}
+
+impl Fold {
+    /// Table with one row per fold. The settings are those selected on the
+    /// other folds, `score` is their score on the fold.
+    pub(crate) fn table(folds: &[Fold]) -> Result<Robj> {
+        let candidates = folds
+            .iter()
+            .map(|fold| fold.training.as_ref().map(|training| training.candidate))
+            .collect::<Vec<Option<Candidate>>>();
+        let count = |value: fn(&Candidate) -> usize| {
+            candidates
+                .iter()
+                .map(|candidate| candidate.as_ref().map(|candidate| value(candidate) as i32))
+                .collect::<Vec<Option<i32>>>()
+        };
+        let columns: Vec<(&str, Robj)> = vec![
+            (
+                "fold",
+                (1..=folds.len() as i32).collect::<Vec<i32>>().into(),
+            ),
+            (
+                "smoothing_iterations",
+                count(|candidate| candidate.smoothing_iterations).into(),
+            ),
+            (
+                "smoothing_window_size",
+                count(|candidate| candidate.smoothing_window_size).into(),
+            ),
+            (
+                "noise_threshold",
+                candidates
+                    .iter()
+                    .map(|candidate| {
+                        candidate
+                            .as_ref()
+                            .map(|candidate| candidate.noise_threshold)
+                    })
+                    .collect::<Vec<Option<f64>>>()
+                    .into(),
+            ),
+            (
+                "fitting_iterations",
+                count(|candidate| candidate.fitting_iterations).into(),
+            ),
+            (
+                "training_score",
+                folds
+                    .iter()
+                    .map(|fold| fold.training.as_ref().and_then(|training| training.score))
+                    .collect::<Vec<Option<f64>>>()
+                    .into(),
+            ),
+            (
+                "score",
+                folds
+                    .iter()
+                    .map(|fold| {
+                        fold.validation
+                            .as_ref()
+                            .and_then(|validation| validation.score)
+                    })
+                    .collect::<Vec<Option<f64>>>()
+                    .into(),
+            ),
+        ];
+
+        util::data_frame(columns, folds.len())
+    }
+}
+
+/// Outcome of deconvoluting with a candidate. For several reference spectra
+/// the MSE and number of peaks are averages.
+#[derive(Clone, Debug)]
+pub(crate) struct Evaluation {
+    pub(crate) candidate: Candidate,
+    pub(crate) mse: Option<f64>,
+    pub(crate) mean_peaks: Option<f64>,
+    pub(crate) score: Option<f64>,
+    pub(crate) error: Option<String>,
+}
+
+impl Evaluation {
+    pub(crate) fn failed(candidate: Candidate, error: String) -> Self {
+        Self {
+            candidate,
+            mse: None,
+            mean_peaks: None,
+            score: None,
+            error: Some(error),
+        }
+    }
+
+    /// The evaluation with the lowest score, if any candidate succeeded.
+    pub(crate) fn best(evaluations: &[Evaluation]) -> Option<&Evaluation> {
+        Self::best_index(evaluations).map(|index| &evaluations[index])
+    }
+
+    /// Index of the evaluation with the lowest score.
+    pub(crate) fn best_index(evaluations: &[Evaluation]) -> Option<usize> {
+        evaluations
+            .iter()
+            .enumerate()
+            .filter(|(_, evaluation)| evaluation.score.is_some_and(|score| score.is_finite()))
+            .min_by(|a, b| a.1.score.unwrap().total_cmp(&b.1.score.unwrap()))
+            .map(|(index, _)| index)
+    }
+
+    pub(crate) fn to_list(&self) -> Result<List> {
+        let mut result = HashMap::<&str, Robj>::new();
+        result.insert(
+            "smoothing_iterations",
+            self.candidate.smoothing_iterations.into(),
+        );
+        result.insert(
+            "smoothing_window_size",
+            self.candidate.smoothing_window_size.into(),
+        );
+        result.insert("noise_threshold", self.candidate.noise_threshold.into());
+        result.insert(
+            "fitting_iterations",
+            self.candidate.fitting_iterations.into(),
+        );
+        result.insert("mse", self.mse.into());
+        result.insert("mean_peaks", self.mean_peaks.into());
+        result.insert("score", self.score.into());
+
+        List::from_hashmap(result)
+    }
+
+    /// Table of all evaluations, one row per candidate.
+    pub(crate) fn table(evaluations: &[Evaluation]) -> Result<Robj> {
+        let candidates = evaluations
+            .iter()
+            .map(|evaluation| evaluation.candidate)
+            .collect::<Vec<Candidate>>();
+        let columns: Vec<(&str, Robj)> = vec![
+            (
+                "smoothing_iterations",
+                candidates
+                    .iter()
+                    .map(|candidate| candidate.smoothing_iterations)
+                    .collect::<Vec<usize>>()
+                    .into(),
+            ),
+            (
+                "smoothing_window_size",
+                candidates
+                    .iter()
+                    .map(|candidate| candidate.smoothing_window_size)
+                    .collect::<Vec<usize>>()
+                    .into(),
+            ),
+            (
+                "noise_threshold",
+                candidates
+                    .iter()
+                    .map(|candidate| candidate.noise_threshold)
+                    .collect::<Vec<f64>>()
+                    .into(),
+            ),
+            (
+                "fitting_iterations",
+                candidates
+                    .iter()
+                    .map(|candidate| candidate.fitting_iterations)
+                    .collect::<Vec<usize>>()
+                    .into(),
+            ),
+            (
+                "mse",
+                evaluations
+                    .iter()
+                    .map(|evaluation| evaluation.mse)
+                    .collect::<Vec<Option<f64>>>()
+                    .into(),
+            ),
+            (
+                "mean_peaks",
+                evaluations
+                    .iter()
+                    .map(|evaluation| evaluation.mean_peaks)
+                    .collect::<Vec<Option<f64>>>()
+                    .into(),
+            ),
+            (
+                "score",
+                evaluations
+                    .iter()
+                    .map(|evaluation| evaluation.score)
+                    .collect::<Vec<Option<f64>>>()
+                    .into(),
+            ),
+            (
+                "error",
+                evaluations
+                    .iter()
+                    .map(|evaluation| evaluation.error.clone())
+                    .collect::<Vec<Option<String>>>()
+                    .into(),
+            ),
+        ];
+
+        util::data_frame(columns, evaluations.len())
+    }
+}
@@ -0,0 +1,488 @@
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Labelled data records whose values are data tables rather than
+/// parameters. Their contents are skipped.
+const DATA_RECORDS: [&str; 5] = ["XYDATA", "XYPOINTS", "PEAK TABLE", "DATA TABLE", "NTUPLES"];
+
+/// Parses the `##LABEL= value` records of a JCAMP-DX file, such as a Bruker
+/// `acqus` or `procs` file or the header of a JCAMP-DX spectrum. Bruker
+/// specific labels (`##$LABEL`) are stored without the `$`, values spanning
+/// several lines are joined with spaces and comments (`$$`) are removed.
+pub(crate) fn read_jcamp_parameters<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, String>> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .map_err(|error| Error::from(format!("{}: {}", path.display(), error)))?;
+    let mut parameters = BTreeMap::<String, String>::new();
+    let mut current: Option<String> = None;
+    for line in content.lines() {
+        let line = match line.find("$$") {
+            Some(index) => &line[..index],
+            None => line,
+        };
+        if let Some(record) = line.strip_prefix("##") {
+            let (label, value) = match record.split_once('=') {
+                Some((label, value)) => (label, value),
+                None => (record, ""),
+            };
+            let label = label.trim().trim_start_matches('$').to_string();
+            if label == "END" {
+                current = None;
+                continue;
+            }
+            if DATA_RECORDS.contains(&label.as_str()) {
+                current = None;
+            } else {
+                current = Some(label.clone());
+            }
+            parameters.insert(label, clean_value(value));
+        } else if let Some(label) = &current {
+            let line = clean_value(line);
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(value) = parameters.get_mut(label) {
+                if !value.is_empty() {
+                    value.push(' ');
+                }
+                value.push_str(&line);
+            }
+        }
+    }
+
+    Ok(parameters)
+}
+
+fn clean_value(value: &str) -> String {
+    let value = value.trim();
+    match value
+        .strip_prefix('<')
+        .and_then(|value| value.strip_suffix('>'))
+    {
+        Some(value) => value.trim().to_string(),
+        None => value.to_string(),
+    }
+}
+
+/// Reads a numeric parameter, if present.
+pub(crate) fn number(parameters: &BTreeMap<String, String>, label: &str) -> Option<f64> {
+    parameters
+        .get(label)
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|value| value.parse::<f64>().ok())
+}
+
+/// Parses a value written as a plain decimal number, e.g. `-12`, `0.5` or
+/// `1.2e-3`. Other values, including `NaN`, `inf` and numbers with leading
+/// zeros such as the ID `007`, are not treated as numbers.
+pub(crate) fn parse_decimal(value: &str) -> Option<f64> {
+    let digits = |value: &str| !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit());
+    let unsigned = value.strip_prefix(['-', '+']).unwrap_or(value);
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (unsigned, None),
+    };
+    let (integer, fraction) = match mantissa.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (mantissa, None),
+    };
+    let plain = digits(integer)
+        && (integer == "0" || !integer.starts_with('0'))
+        && fraction.map_or(true, digits)
+        && exponent.map_or(true, |exponent| {
+            digits(exponent.strip_prefix(['-', '+']).unwrap_or(exponent))
+        });
+
+    plain.then(|| value.parse::<f64>().ok()).flatten()
+}
+
+/// Reads the element at `index` of an array parameter such as `P` or `D`,
+/// whose value starts with its index range, e.g. `(0..63) 10 12.5 ...`.
+pub(crate) fn number_at(
+    parameters: &BTreeMap<String, String>,
+    label: &str,
+    index: usize,
+) -> Option<f64> {
+    parameters
+        .get(label)?
+        .split_whitespace()
+        .filter(|value| !value.starts_with('('))
+        .nth(index)
+        .and_then(|value| value.parse::<f64>().ok())
+}
+
+/// Acquisition and processing parameters of a Bruker spectrum that are
+/// relevant for comparing intensities across runs.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Acquisition {
+    /// Pulse program (`PULPROG`).
+    pub(crate) pulse_program: Option<String>,
+    /// Observed nucleus (`NUC1`).
+    pub(crate) nucleus: Option<String>,
+    /// Solvent (`SOLVENT`).
+    pub(crate) solvent: Option<String>,
+    /// Number of scans (`NS`).
+    pub(crate) ns: Option<f64>,
+    /// Number of dummy scans (`DS`).
+    pub(crate) ds: Option<f64>,
+    /// Receiver gain (`RG`).
+    pub(crate) rg: Option<f64>,
+    /// Length of the 90 degree pulse in µs (`P1`).
+    pub(crate) p1: Option<f64>,
+    /// Relaxation delay in s (`D1`).
+    pub(crate) d1: Option<f64>,
+    /// Sample temperature in K (`TE`).
+    pub(crate) te: Option<f64>,
+    /// Number of acquired data points (`TD`).
+    pub(crate) td: Option<f64>,
+    /// Spectral width in ppm (`SW`).
+    pub(crate) sw: Option<f64>,
+    /// Transmitter frequency in MHz (`SFO1`).
+    pub(crate) sfo1: Option<f64>,
+    /// Transmitter offset in Hz (`O1`).
+    pub(crate) o1: Option<f64>,
+    /// Number of processed data points (`SI`).
+    pub(crate) si: Option<f64>,
+    /// Line broadening in Hz (`LB`).
+    pub(crate) lb: Option<f64>,
+    /// Intensity scaling exponent of the processed data (`NC_proc`), the
+    /// intensities stored in the file are the true ones divided by
+    /// `2^NC_proc`.
+    pub(crate) nc_proc: Option<f64>,
+}
+
+impl Acquisition {
+    pub(crate) fn to_list(&self) -> Result<List> {
+        let text = |value: &Option<String>| Nullable::from(value.clone()).into();
+        let number = |value: Option<f64>| Nullable::from(value).into();
+        let mut result = HashMap::<&str, Robj>::new();
+        result.insert("pulse_program", text(&self.pulse_program));
+        result.insert("nucleus", text(&self.nucleus));
+        result.insert("solvent", text(&self.solvent));
+        result.insert("ns", number(self.ns));
+        result.insert("ds", number(self.ds));
+        result.insert("rg", number(self.rg));
+        result.insert("p1", number(self.p1));
+        result.insert("d1", number(self.d1));
+        result.insert("te", number(self.te));
+        result.insert("td", number(self.td));
+        result.insert("sw", number(self.sw));
+        result.insert("sfo1", number(self.sfo1));
+        result.insert("o1", number(self.o1));
+        result.insert("si", number(self.si));
+        result.insert("lb", number(self.lb));
+        result.insert("nc_proc", number(self.nc_proc));
+
+        List::from_hashmap(result)
+    }
+
+    /// Factor converting intensities to a common acquisition basis, such
+    /// that intensities are proportional to the concentration across runs
+    /// (PULCON). The intensities are divided by the number of scans and the
+    /// receiver gain relative to the basis. `NC_proc` is not applied, as the
+    /// Bruker reader of `metabodecon` already scales the stored data by
+    /// `2^NC_proc`.
+    ///
+    /// The pulse length and temperature corrections are each applied only if
+    /// the basis gives P1 or TE, multiplying by the value of the spectrum
+    /// relative to the basis; a basis without them yields a partial
+    /// correction that is only valid for spectra acquired with the same pulse
+    /// length and at the same temperature. Without a basis, intensities are
+    /// scaled to a single scan at a receiver gain of 1.
+    pub(crate) fn scaling(&self, basis: &Acquisition) -> Result<f64> {
+        let required = |value: Option<f64>, label: &str| {
+            value
+                .filter(|value| *value > 0.0)
+                .ok_or_else(|| Error::from(format!("the spectrum has no valid {}", label)))
+        };
+        let ns = required(self.ns, "NS")?;
+        let rg = required(self.rg, "RG")?;
+        let mut factor = 1.0 / (ns / basis.ns.unwrap_or(1.0)) / (rg / basis.rg.unwrap_or(1.0));
+        if let Some(p1) = basis.p1 {
+            factor *= required(self.p1, "P1")? / p1;
+        }
+        if let Some(te) = basis.te {
+            factor *= required(self.te, "TE")? / te;
+        }
+
+        Ok(factor)
+    }
+
+    /// Parameters of the spectrum once it has been scaled to `basis` with
+    /// [`Acquisition::scaling`]. NS and RG take the values of the basis, P1
+    /// and TE only if the basis gives them, so that scaling again to the same
+    /// basis leaves the spectrum unchanged.
+    pub(crate) fn scaled_to(&self, basis: &Acquisition) -> Acquisition {
+        Acquisition {
+            ns: Some(basis.ns.unwrap_or(1.0)),
+            rg: Some(basis.rg.unwrap_or(1.0)),
+            p1: basis.p1.or(self.p1),
+            te: basis.te.or(self.te),
+            ..self.clone()
+        }
+    }
+}
+
+impl TryFrom<List> for Acquisition {
+    type Error = Error;
+
+    /// Reads the basis of [`Acquisition::scaling`] from a list with the
+    /// optional elements `ns`, `rg`, `p1` and `te`, such as the one returned
+    /// by `Spectrum$acquisition()`. Other elements are ignored.
+    fn try_from(value: List) -> Result<Self> {
+        let mut basis = Self::default();
+        for (name, value) in value.iter() {
+            if value.is_null() {
+                continue;
+            }
+            let number = || {
+                value
+                    .as_real()
+                    .filter(|value| value.is_finite() && *value > 0.0)
+                    .ok_or_else(|| Error::from(format!("{} must be a positive number", name)))
+                    .map(Some)
+            };
+            match name {
+                "ns" => basis.ns = number()?,
+                "rg" => basis.rg = number()?,
+                "p1" => basis.p1 = number()?,
+                "te" => basis.te = number()?,
+                _ => (),
+            }
+        }
+
+        Ok(basis)
+    }
+}
+
+/// Reads the acquisition parameters of a Bruker spectrum from its `acqus`
+/// and `procs` files.
+pub(crate) fn bruker_acquisition<P: AsRef<Path>>(
+    path: P,
+    experiment: u32,
+    processing: u32,
+) -> Result<Acquisition> {
+    let path = path.as_ref();
+    let acqus = read_jcamp_parameters(path.join(experiment.to_string()).join("acqus"))?;
+    let procs =
+        read_jcamp_parameters(bruker_processing_dir(path, experiment, processing).join("procs"))?;
+    let text = |label: &str| acqus.get(label).filter(|value| !value.is_empty()).cloned();
+
+    Ok(Acquisition {
+        pulse_program: text("PULPROG"),
+        nucleus: text("NUC1"),
+        solvent: text("SOLVENT"),
+        ns: number(&acqus, "NS"),
+        ds: number(&acqus, "DS"),
+        rg: number(&acqus, "RG"),
+        p1: number_at(&acqus, "P", 1),
+        d1: number_at(&acqus, "D", 1),
+        te: number(&acqus, "TE"),
+        td: number(&acqus, "TD"),
+        sw: number(&acqus, "SW"),
+        sfo1: number(&acqus, "SFO1"),
+        o1: number(&acqus, "O1"),
+        si: number(&procs, "SI"),
+        lb: number(&procs, "LB"),
+        nc_proc: number(&procs, "NC_proc"),
+    })
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD hh:mm:ss` in UTC.
+pub(crate) fn format_timestamp(seconds: i64) -> String {
+    let days = seconds.div_euclid(86_400);
+    let time = seconds.rem_euclid(86_400);
+    // Civil date from days since 1970-01-01 (proleptic Gregorian calendar).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+/// Adds the commonly used entries `title`, `pulse_program`, `temperature`,
+/// `solvent` and `date` to the metadata, taking the first of the given
+/// labels that is present for each.
+fn add_common_entries(metadata: &mut BTreeMap<String, String>, labels: &[(&str, &[&str])]) {
+    for (key, candidates) in labels {
+        if metadata.contains_key(*key) {
+            continue;
+        }
+        if let Some(value) = candidates
+            .iter()
+            .filter_map(|label| metadata.get(*label))
+            .find(|value| !value.is_empty())
+            .cloned()
+        {
+            metadata.insert(key.to_string(), value);
+        }
+    }
+}
+
+/// All parameters of a Bruker spectrum as a key/value map. The parameters of
+/// `acqus` and `procs` are prefixed with `acqus.` and `procs.`, the content
+/// of the `title` file is stored as `title` and the acquisition date as
+/// `date`, together with `pulse_program`, `temperature` and `solvent`.
+pub(crate) fn bruker_metadata<P: AsRef<Path>>(
+    path: P,
+    experiment: u32,
+    processing: u32,
+) -> Result<BTreeMap<String, String>> {
+    let path = path.as_ref();
+    let processing_dir = bruker_processing_dir(path, experiment, processing);
+    let acqus = read_jcamp_parameters(path.join(experiment.to_string()).join("acqus"))?;
+    let procs = read_jcamp_parameters(processing_dir.join("procs"))?;
+    let mut metadata = BTreeMap::<String, String>::new();
+    if let Ok(title) = std::fs::read_to_string(processing_dir.join("title")) {
+        let title = title.trim();
+        if !title.is_empty() {
+            metadata.insert("title".to_string(), title.to_string());
+        }
+    }
+    if let Some(date) = acqus
+        .get("DATE")
+        .and_then(|date| date.trim().parse::<i64>().ok())
+    {
+        metadata.insert("date".to_string(), format_timestamp(date));
+    }
+    metadata.extend(
+        acqus
+            .into_iter()
+            .map(|(label, value)| (format!("acqus.{}", label), value)),
+    );
+    metadata.extend(
+        procs
+            .into_iter()
+            .map(|(label, value)| (format!("procs.{}", label), value)),
+    );
+    add_common_entries(
+        &mut metadata,
+        &[
+            ("pulse_program", &["acqus.PULPROG"]),
+            ("temperature", &["acqus.TE"]),
+            ("solvent", &["acqus.SOLVENT"]),
+        ],
+    );
+
+    Ok(metadata)
+}
+
+/// All header parameters of a JCAMP-DX spectrum as a key/value map, prefixed
+/// with `jcampdx.`, together with the entries `title`, `pulse_program`,
+/// `temperature`, `solvent` and `date` if the header provides them.
+pub(crate) fn jcampdx_metadata<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, String>> {
+    let mut metadata = read_jcamp_parameters(path)?
+        .into_iter()
+        .map(|(label, value)| (format!("jcampdx.{}", label), value))
+        .collect::<BTreeMap<String, String>>();
+    add_common_entries(
+        &mut metadata,
+        &[
+            ("title", &["jcampdx.TITLE"]),
+            (
+                "pulse_program",
+                &["jcampdx.PULPROG", "jcampdx..PULSE SEQUENCE"],
+            ),
+            ("temperature", &["jcampdx.TE", "jcampdx.TEMPERATURE"]),
+            ("solvent", &["jcampdx.SOLVENT", "jcampdx..SOLVENT NAME"]),
+            ("date", &["jcampdx.LONGDATE", "jcampdx.DATE"]),
+        ],
+    );
+
+    Ok(metadata)
+}
+
+/// Directory of the processed data of a Bruker experiment.
+pub(crate) fn bruker_processing_dir<P: AsRef<Path>>(
+    path: P,
+    experiment: u32,
+    processing: u32,
+) -> PathBuf {
+    path.as_ref()
+        .join(experiment.to_string())
+        .join("pdata")
+        .join(processing.to_string())
+}
+
+/// Sample directories of a set of Bruker spectra, i.e. the subdirectories of
+/// `path` that contain the given experiment.
+pub(crate) fn bruker_samples<P: AsRef<Path>>(path: P, experiment: u32) -> Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let entries = std::fs::read_dir(path)
+        .map_err(|error| Error::from(format!("{}: {}", path.display(), error)))?;
+    let mut samples = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|sample| sample.join(experiment.to_string()).is_dir())
+        .collect::<Vec<PathBuf>>();
+    samples.sort();
+
+    Ok(samples)
+}
+
+/// Chemical shift range `(lower, upper)` in ppm of a Bruker spectrum,
+/// computed from `OFFSET`, `SW_p` and `SF` in its `procs` file.
+pub(crate) fn bruker_range<P: AsRef<Path>>(
+    path: P,
+    experiment: u32,
+    processing: u32,
+) -> Result<(f64, f64)> {
+    let procs = bruker_processing_dir(path, experiment, processing).join("procs");
+    let parameters = read_jcamp_parameters(&procs)?;
+    match (
+        number(&parameters, "OFFSET"),
+        number(&parameters, "SW_p"),
+        number(&parameters, "SF"),
+    ) {
+        (Some(offset), Some(width), Some(frequency)) if frequency > 0.0 => {
+            Ok((offset - width / frequency, offset))
+        }
+        _ => Err(Error::from(format!(
+            "{}: missing OFFSET, SW_p or SF",
+            procs.display()
+        ))),
+    }
+}
+
+/// Chemical shift range `(lower, upper)` in ppm of a JCAMP-DX spectrum,
+/// computed from `FIRSTX`, `LASTX`, `XUNITS` and `.OBSERVE FREQUENCY`.
+pub(crate) fn jcampdx_range<P: AsRef<Path>>(path: P) -> Result<(f64, f64)> {
+    let path = path.as_ref();
+    let parameters = read_jcamp_parameters(path)?;
+    let first = number(&parameters, "FIRSTX");
+    let last = number(&parameters, "LASTX");
+    let units = parameters
+        .get("XUNITS")
+        .map(|units| units.to_uppercase())
+        .unwrap_or_default();
+    let frequency = number(&parameters, ".OBSERVE FREQUENCY");
+    let (first, last) = match (first, last, units.as_str(), frequency) {
+        (Some(first), Some(last), "PPM", _) => (first, last),
+        (Some(first), Some(last), "HZ", Some(frequency)) if frequency > 0.0 => {
+            (first / frequency, last / frequency)
+        }
+        _ => {
+            return Err(Error::from(format!(
+                "{}: cannot determine the chemical shift range",
+                path.display()
+            )))
+        }
+    };
+
+    Ok((first.min(last), first.max(last)))
+}
@@ -0,0 +1,226 @@
+use extendr_api::prelude::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Spectrometer frequency in MHz at which preset regions are reported if no
+/// spectrum or frequency is given.
+pub(crate) const DEFAULT_FREQUENCY: f64 = 600.0;
+
+/// Region of a preset, given by its centre in ppm and its full width in Hz.
+/// Specifying the width in Hz makes the region scale with the field strength,
+/// so that it covers the same multiplet or suppression band on every
+/// spectrometer.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub(crate) struct PresetRegion {
+    pub(crate) centre: f64,
+    pub(crate) width: f64,
+}
+
+impl PresetRegion {
+    const fn new(centre: f64, width: f64) -> Self {
+        Self { centre, width }
+    }
+
+    /// Region `(start, end)` in ppm at the given spectrometer frequency in
+    /// MHz.
+    pub(crate) fn at_frequency(&self, frequency: f64) -> (f64, f64) {
+        let half_width = 0.5 * self.width / frequency;
+
+        (self.centre - half_width, self.centre + half_width)
+    }
+}
+
+/// Named set of ignore regions for one nucleus.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Preset {
+    pub(crate) name: String,
+    pub(crate) nucleus: String,
+    pub(crate) regions: Vec<PresetRegion>,
+}
+
+impl Preset {
+    /// Regions `(start, end)` in ppm at the given spectrometer frequency in
+    /// MHz.
+    pub(crate) fn at_frequency(&self, frequency: f64) -> Vec<(f64, f64)> {
+        self.regions
+            .iter()
+            .map(|region| region.at_frequency(frequency))
+            .collect()
+    }
+}
+
+/// Built-in presets as `(name, nucleus, regions)`. Chemical shifts are those
+/// of the compounds in aqueous solution.
+const BUILT_IN: &[(&str, &str, &[PresetRegion])] = &[
+    ("water", "1H", &[PresetRegion::new(4.79, 150.0)]),
+    ("urea", "1H", &[PresetRegion::new(5.78, 180.0)]),
+    ("dmso", "1H", &[PresetRegion::new(2.71, 20.0)]),
+    ("methanol", "1H", &[PresetRegion::new(3.36, 20.0)]),
+    (
+        "ethanol",
+        "1H",
+        &[PresetRegion::new(1.18, 40.0), PresetRegion::new(3.65, 50.0)],
+    ),
+    (
+        "edta",
+        "1H",
+        &[
+            PresetRegion::new(2.56, 20.0),
+            PresetRegion::new(2.70, 20.0),
+            PresetRegion::new(3.10, 30.0),
+            PresetRegion::new(3.23, 30.0),
+            PresetRegion::new(3.60, 20.0),
+        ],
+    ),
+    ("tsp", "1H", &[PresetRegion::new(0.0, 30.0)]),
+    (
+        "dss",
+        "1H",
+        &[
+            PresetRegion::new(0.0, 30.0),
+            PresetRegion::new(0.63, 40.0),
+            PresetRegion::new(1.76, 40.0),
+            PresetRegion::new(2.91, 40.0),
+        ],
+    ),
+    ("dmso", "13C", &[PresetRegion::new(39.39, 100.0)]),
+    ("methanol", "13C", &[PresetRegion::new(49.50, 100.0)]),
+    (
+        "ethanol",
+        "13C",
+        &[
+            PresetRegion::new(17.47, 100.0),
+            PresetRegion::new(58.05, 100.0),
+        ],
+    ),
+    ("tsp", "13C", &[PresetRegion::new(-1.7, 100.0)]),
+    ("dss", "13C", &[PresetRegion::new(0.0, 100.0)]),
+];
+
+/// Presets registered at runtime, keyed by `(name, nucleus)`. They take
+/// precedence over the built-in presets.
+static CUSTOM: Mutex<BTreeMap<(String, String), Vec<PresetRegion>>> = Mutex::new(BTreeMap::new());
+
+/// Normalises the notations of a nucleus used by Bruker, JCAMP-DX and
+/// `metabodecon` (e.g. `"1H"`, `"H1"`, `"Hydrogen1"`) to the form `"1H"`.
+pub(crate) fn normalise_nucleus(nucleus: &str) -> String {
+    let nucleus = nucleus
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    match nucleus.as_str() {
+        "1h" | "h1" | "h" | "hydrogen" | "hydrogen1" | "proton" => "1H".to_string(),
+        "13c" | "c13" | "c" | "carbon" | "carbon13" => "13C".to_string(),
+        "15n" | "n15" | "nitrogen15" => "15N".to_string(),
+        "19f" | "f19" | "fluorine19" => "19F".to_string(),
+        "31p" | "p31" | "phosphorus31" => "31P".to_string(),
+        _ => nucleus,
+    }
+}
+
+fn normalise_name(name: &str) -> String {
+    match name.to_lowercase().as_str() {
+        "tsp/dss" | "dss/tsp" => "dss".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Looks up a preset for the given nucleus.
+pub(crate) fn lookup(name: &str, nucleus: &str) -> Result<Preset> {
+    let name = normalise_name(name);
+    let nucleus = normalise_nucleus(nucleus);
+    let custom = CUSTOM
+        .lock()
+        .map_err(|error| Error::from(error.to_string()))?
+        .get(&(name.clone(), nucleus.clone()))
+        .cloned();
+    custom
+        .or_else(|| {
+            BUILT_IN
+                .iter()
+                .find(|(preset, preset_nucleus, _)| *preset == name && *preset_nucleus == nucleus)
+                .map(|(_, _, regions)| regions.to_vec())
+        })
+        .map(|regions| Preset {
+            name: name.clone(),
+            nucleus: nucleus.clone(),
+            regions,
+        })
+        .ok_or_else(|| {
+            Error::from(format!(
+                "no ignore region preset {:?} for nucleus {}",
+                name, nucleus
+            ))
+        })
+}
+
+/// All available presets, registered ones first.
+pub(crate) fn all() -> Result<Vec<Preset>> {
+    let custom = CUSTOM
+        .lock()
+        .map_err(|error| Error::from(error.to_string()))?;
+    let mut presets = custom
+        .iter()
+        .map(|((name, nucleus), regions)| Preset {
+            name: name.clone(),
+            nucleus: nucleus.clone(),
+            regions: regions.clone(),
+        })
+        .collect::<Vec<Preset>>();
+    presets.extend(
+        BUILT_IN
+            .iter()
+            .filter(|(name, nucleus, _)| {
+                !custom.contains_key(&(name.to_string(), nucleus.to_string()))
+            })
+            .map(|(name, nucleus, regions)| Preset {
+                name: name.to_string(),
+                nucleus: nucleus.to_string(),
+                regions: regions.to_vec(),
+            }),
+    );
+
+    Ok(presets)
+}
+
+/// Registers the presets defined in a JSON file, given as an array of
+/// `{"name": , "nucleus": , "regions": [{"centre": , "width": }]}` objects
+/// with centres in ppm and widths in Hz. Returns the number of presets read.
+pub(crate) fn register<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .map_err(|error| Error::from(format!("{}: {}", path.display(), error)))?;
+    let presets = serde_json::from_str::<Vec<Preset>>(&content)
+        .map_err(|error| Error::from(format!("{}: {}", path.display(), error)))?;
+    if let Some(preset) = presets.iter().find(|preset| {
+        preset.regions.is_empty()
+            || preset
+                .regions
+                .iter()
+                .any(|region| !(region.centre.is_finite() && region.width > 0.0))
+    }) {
+        return Err(Error::from(format!(
+            "{}: preset {:?} must have regions with a finite centre and a positive width",
+            path.display(),
+            preset.name
+        )));
+    }
+    let count = presets.len();
+    let mut custom = CUSTOM
+        .lock()
+        .map_err(|error| Error::from(error.to_string()))?;
+    presets.into_iter().for_each(|preset| {
+        custom.insert(
+            (
+                normalise_name(&preset.name),
+                normalise_nucleus(&preset.nucleus),
+            ),
+            preset.regions,
+        );
+    });
+
+    Ok(count)
+}
@@ -0,0 +1,161 @@
+use extendr_api::prelude::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+fn default_nucleus() -> String {
+    "1H".to_string()
+}
+
+/// Deconvoluter settings for a sample type and field strength.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Profile {
+    pub(crate) name: String,
+    pub(crate) smoothing_iterations: usize,
+    pub(crate) smoothing_window_size: usize,
+    pub(crate) noise_threshold: f64,
+    pub(crate) fitting_iterations: usize,
+    #[serde(default = "default_nucleus")]
+    pub(crate) nucleus: String,
+    /// Spectrometer frequency in MHz the profile is intended for. The ignore
+    /// presets are scaled to the frequency of each deconvoluted spectrum.
+    #[serde(default)]
+    pub(crate) frequency: Option<f64>,
+    /// Names of the ignore region presets added by the profile.
+    #[serde(default)]
+    pub(crate) ignore_presets: Vec<String>,
+    /// Whether the profile is one of the built-in examples rather than a
+    /// registered one.
+    #[serde(skip)]
+    pub(crate) example: bool,
+}
+
+/// Settings of a built-in example profile.
+struct Matrix {
+    name: &'static str,
+    smoothing_iterations: usize,
+    smoothing_window_size: usize,
+    noise_threshold: f64,
+    fitting_iterations: usize,
+    ignore_presets: &'static [&'static str],
+}
+
+/// Built-in example profiles, one per sample type and not tied to a field
+/// strength. The settings are unvalidated starting points (the urine settings
+/// equal the defaults of the deconvoluter) that show the format of a profile;
+/// validated profiles are meant to be registered from a JSON file.
+const MATRICES: [Matrix; 4] = [
+    Matrix {
+        name: "urine",
+        smoothing_iterations: 2,
+        smoothing_window_size: 5,
+        noise_threshold: 6.4,
+        fitting_iterations: 10,
+        ignore_presets: &["water", "urea"],
+    },
+    Matrix {
+        name: "serum",
+        smoothing_iterations: 3,
+        smoothing_window_size: 7,
+        noise_threshold: 8.0,
+        fitting_iterations: 12,
+        ignore_presets: &["water"],
+    },
+    Matrix {
+        name: "plasma",
+        smoothing_iterations: 3,
+        smoothing_window_size: 7,
+        noise_threshold: 8.0,
+        fitting_iterations: 12,
+        ignore_presets: &["water", "edta"],
+    },
+    Matrix {
+        name: "cell_extract",
+        smoothing_iterations: 2,
+        smoothing_window_size: 3,
+        noise_threshold: 5.0,
+        fitting_iterations: 10,
+        ignore_presets: &["water"],
+    },
+];
+
+/// Profiles registered at runtime, keyed by their lowercase name. They take
+/// precedence over the built-in profiles.
+static CUSTOM: Mutex<BTreeMap<String, Profile>> = Mutex::new(BTreeMap::new());
+
+fn built_in() -> Vec<Profile> {
+    MATRICES
+        .iter()
+        .map(|matrix| Profile {
+            name: matrix.name.to_string(),
+            smoothing_iterations: matrix.smoothing_iterations,
+            smoothing_window_size: matrix.smoothing_window_size,
+            noise_threshold: matrix.noise_threshold,
+            fitting_iterations: matrix.fitting_iterations,
+            nucleus: default_nucleus(),
+            frequency: None,
+            ignore_presets: matrix
+                .ignore_presets
+                .iter()
+                .map(|preset| preset.to_string())
+                .collect(),
+            example: true,
+        })
+        .collect()
+}
+
+/// Key of a profile name. Names are compared case-insensitively.
+fn key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+/// Looks up a profile by name.
+pub(crate) fn lookup(name: &str) -> Result<Profile> {
+    if let Some(profile) = CUSTOM
+        .lock()
+        .map_err(|error| Error::from(error.to_string()))?
+        .get(&key(name))
+    {
+        return Ok(profile.clone());
+    }
+    built_in()
+        .into_iter()
+        .find(|profile| key(&profile.name) == key(name))
+        .ok_or_else(|| Error::from(format!("unknown deconvoluter profile {:?}", name)))
+}
+
+/// All available profiles, registered ones first.
+pub(crate) fn all() -> Result<Vec<Profile>> {
+    let custom = CUSTOM
+        .lock()
+        .map_err(|error| Error::from(error.to_string()))?;
+    let mut profiles = custom.values().cloned().collect::<Vec<Profile>>();
+    profiles.extend(
+        built_in()
+            .into_iter()
+            .filter(|profile| !custom.contains_key(&key(&profile.name))),
+    );
+
+    Ok(profiles)
+}
+
+/// Registers the profiles defined in a JSON file, given as an array of
+/// objects with the fields of [`Profile`]. Returns the number of profiles
+/// read.
+pub(crate) fn register<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .map_err(|error| Error::from(format!("{}: {}", path.display(), error)))?;
+    let profiles = serde_json::from_str::<Vec<Profile>>(&content)
+        .map_err(|error| Error::from(format!("{}: {}", path.display(), error)))?;
+    let count = profiles.len();
+    let mut custom = CUSTOM
+        .lock()
+        .map_err(|error| Error::from(error.to_string()))?;
+    profiles.into_iter().for_each(|profile| {
+        custom.insert(key(&profile.name), profile);
+    });
+
+    Ok(count)
+}
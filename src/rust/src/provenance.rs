@@ -0,0 +1,190 @@
+use crate::parameters;
+use crate::spectrum::Spectrum;
+use crate::targets;
+use extendr_api::prelude::*;
+use metabodecon::deconvolution::Lorentzian;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version and revision of the metabodecon crate. The revision must match the
+/// `rev` of the dependency in `Cargo.toml`, which the CI checks.
+const METABODECON_VERSION: &str = "0.1.0 (9cfd0bbd718ffc16d333780a449407b25e5f6d84)";
+
+/// How the peaks of a deconvolution were obtained.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) enum Mode {
+    /// Peaks detected and fitted from scratch.
+    #[default]
+    Untargeted,
+    /// Peaks refined from those of an earlier deconvolution, identified by
+    /// the hash of its peaks, with or without running the peak detection.
+    WarmStart { detect: bool, initial_hash: String },
+    /// One peak fitted per target.
+    Targeted { targets: Vec<targets::Target> },
+}
+
+impl Mode {
+    /// Warm start from the given initial peaks.
+    pub(crate) fn warm_start(initial: &[Lorentzian], detect: bool) -> Self {
+        let values = initial
+            .iter()
+            .flat_map(|peak| [peak.sfhw(), peak.hw2(), peak.maxp()]);
+
+        Self::WarmStart {
+            detect,
+            initial_hash: fnv(values),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Untargeted => "untargeted",
+            Self::WarmStart { .. } => "warm_start",
+            Self::Targeted { .. } => "targeted",
+        }
+    }
+}
+
+/// Record of how a deconvolution was made.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Provenance {
+    /// Settings of the deconvoluter, as returned by `Deconvoluter$settings()`.
+    pub(crate) settings: serde_json::Value,
+    pub(crate) mdrb_version: String,
+    pub(crate) metabodecon_version: String,
+    /// Number of threads available to the deconvolution.
+    pub(crate) threads: usize,
+    /// Time of the deconvolution in UTC.
+    pub(crate) timestamp: String,
+    /// Hash of the chemical shifts, intensities and signal boundaries of the
+    /// input spectrum.
+    pub(crate) input_hash: String,
+    /// File or directory the input spectrum was read from, if known.
+    pub(crate) source: Option<String>,
+    #[serde(default)]
+    pub(crate) mode: Mode,
+}
+
+impl Provenance {
+    pub(crate) fn new(
+        settings: serde_json::Value,
+        threads: usize,
+        spectrum: &Spectrum,
+        mode: Mode,
+    ) -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+
+        Self {
+            settings,
+            mdrb_version: env!("CARGO_PKG_VERSION").to_string(),
+            metabodecon_version: METABODECON_VERSION.to_string(),
+            threads,
+            timestamp: format!("{} UTC", parameters::format_timestamp(seconds)),
+            input_hash: hash(spectrum),
+            source: spectrum.source_path().map(|source| source.to_string()),
+            mode,
+        }
+    }
+
+    pub(crate) fn to_list(&self) -> Result<List> {
+        let mut result = HashMap::<&str, Robj>::new();
+        result.insert("settings", json_to_robj(&self.settings)?);
+        result.insert("mdrb_version", self.mdrb_version.clone().into());
+        result.insert(
+            "metabodecon_version",
+            self.metabodecon_version.clone().into(),
+        );
+        result.insert("threads", self.threads.into());
+        result.insert("timestamp", self.timestamp.clone().into());
+        result.insert("input_hash", self.input_hash.clone().into());
+        result.insert("source", Nullable::from(self.source.clone()).into());
+        result.insert("mode", self.mode.name().into());
+        let (detect, initial_hash, targets) = match &self.mode {
+            Mode::Untargeted => (None, None, None),
+            Mode::WarmStart {
+                detect,
+                initial_hash,
+            } => (Some(*detect), Some(initial_hash.clone()), None),
+            Mode::Targeted { targets } => (None, None, Some(targets::table(targets)?)),
+        };
+        result.insert("detect", Nullable::from(detect).into());
+        result.insert("initial_hash", Nullable::from(initial_hash).into());
+        result.insert("targets", Nullable::from(targets).into());
+
+        List::from_hashmap(result)
+    }
+}
+
+/// 64-bit FNV-1a hash of the data of a spectrum as a hexadecimal string.
+/// Unlike the hashers of the standard library, it is stable across Rust
+/// versions and platforms.
+fn hash(spectrum: &Spectrum) -> String {
+    let spectrum: &metabodecon::spectrum::Spectrum = spectrum.as_ref();
+    let (start, end) = spectrum.signal_boundaries();
+    let boundaries = [start, end];
+    let values = spectrum
+        .chemical_shifts()
+        .iter()
+        .chain(spectrum.intensities().iter())
+        .chain(boundaries.iter())
+        .copied();
+
+    fnv(values)
+}
+
+/// 64-bit FNV-1a hash of a sequence of numbers as a hexadecimal string.
+fn fnv(values: impl Iterator<Item = f64>) -> String {
+    let hash = values
+        .flat_map(|value| value.to_bits().to_le_bytes())
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+
+    format!("{:016x}", hash)
+}
+
+/// Converts a JSON object into a named list.
+pub(crate) fn json_to_list(value: &serde_json::Value) -> Result<List> {
+    List::try_from(json_to_robj(value)?)
+}
+
+/// Converts a JSON value into the corresponding R object. Objects become
+/// named lists, non-empty arrays of numbers numeric vectors and other arrays
+/// lists.
+fn json_to_robj(value: &serde_json::Value) -> Result<Robj> {
+    use serde_json::Value;
+
+    Ok(match value {
+        Value::Null => ().into(),
+        Value::Bool(value) => (*value).into(),
+        Value::Number(value) => value.as_f64().unwrap_or(f64::NAN).into(),
+        Value::String(value) => value.clone().into(),
+        Value::Array(values) if !values.is_empty() && values.iter().all(Value::is_number) => values
+            .iter()
+            .map(|value| value.as_f64().unwrap_or(f64::NAN))
+            .collect::<Vec<f64>>()
+            .into(),
+        Value::Array(values) => List::from_values(
+            values
+                .iter()
+                .map(json_to_robj)
+                .collect::<Result<Vec<Robj>>>()?,
+        )
+        .into(),
+        Value::Object(values) => {
+            let names = values
+                .keys()
+                .map(|name| name.as_str())
+                .collect::<Vec<&str>>();
+            let values = values
+                .values()
+                .map(json_to_robj)
+                .collect::<Result<Vec<Robj>>>()?;
+            List::from_names_and_values(names, values)?.into()
+        }
+    })
+}
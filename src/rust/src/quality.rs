@@ -0,0 +1,439 @@
+use extendr_api::prelude::*;
+use metabodecon::spectrum;
+
+/// Mean and standard deviation of the noise of a spectrum.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Noise {
+    pub(crate) mean: f64,
+    pub(crate) sd: f64,
+}
+
+/// Estimates the noise from the data points inside `regions`. If no regions
+/// are given, the data points outside the signal boundaries are used. A
+/// straight line is fitted to each region and the standard deviation is
+/// computed from the residuals, so a sloped baseline does not inflate it.
+pub(crate) fn noise(spectrum: &spectrum::Spectrum, regions: &[(f64, f64)]) -> Result<Noise> {
+    let regions = if regions.is_empty() {
+        let chemical_shifts = spectrum.chemical_shifts();
+        let (first, last) = (
+            chemical_shifts[0],
+            chemical_shifts[chemical_shifts.len() - 1],
+        );
+        let (start, end) = spectrum.signal_boundaries();
+        vec![
+            (first.min(last), start.min(end)),
+            (start.max(end), first.max(last)),
+        ]
+    } else {
+        regions.to_vec()
+    };
+    let mut count = 0usize;
+    let mut fitted = 0usize;
+    let mut sum = 0.0;
+    let mut squared_residuals = 0.0;
+    for (start, end) in regions {
+        let (lower, upper) = (start.min(end), start.max(end));
+        let (x, y): (Vec<f64>, Vec<f64>) = spectrum
+            .chemical_shifts()
+            .iter()
+            .zip(spectrum.intensities().iter())
+            .filter(|(x, _)| **x >= lower && **x <= upper)
+            .map(|(x, y)| (*x, *y))
+            .unzip();
+        if x.len() < 3 {
+            continue;
+        }
+        let (intercept, slope) = linear_fit(&x, &y);
+        count += x.len();
+        fitted += 1;
+        sum += y.iter().sum::<f64>();
+        squared_residuals += x
+            .iter()
+            .zip(y.iter())
+            .map(|(x, y)| (y - intercept - slope * x).powi(2))
+            .sum::<f64>();
+    }
+    if fitted == 0 {
+        return Err(Error::from(
+            "too few data points in the noise regions to estimate the noise",
+        ));
+    }
+
+    Ok(Noise {
+        mean: sum / count as f64,
+        // Each fitted line uses up two degrees of freedom.
+        sd: (squared_residuals / (count - 2 * fitted) as f64).sqrt(),
+    })
+}
+
+/// Signal-to-noise ratio of the region `[start, end]`, defined as the
+/// maximum intensity above the mean noise level divided by the noise
+/// standard deviation.
+pub(crate) fn snr(
+    spectrum: &spectrum::Spectrum,
+    start: f64,
+    end: f64,
+    noise: Noise,
+) -> Result<(f64, f64)> {
+    let (lower, upper) = (start.min(end), start.max(end));
+    let signal = spectrum
+        .chemical_shifts()
+        .iter()
+        .zip(spectrum.intensities().iter())
+        .filter(|(x, _)| **x >= lower && **x <= upper)
+        .map(|(_, y)| *y)
+        .fold(f64::NEG_INFINITY, f64::max);
+    if !signal.is_finite() {
+        return Err(Error::from("the signal region contains no data points"));
+    }
+    let signal = signal - noise.mean;
+
+    Ok((signal, signal / noise.sd))
+}
+
+/// Least squares fit of a straight line, returning intercept and slope.
+pub(crate) fn linear_fit(x: &[f64], y: &[f64]) -> (f64, f64) {
+    let n = x.len() as f64;
+    let mean_x = x.iter().sum::<f64>() / n;
+    let mean_y = y.iter().sum::<f64>() / n;
+    let covariance = x
+        .iter()
+        .zip(y.iter())
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum::<f64>();
+    let variance = x.iter().map(|x| (x - mean_x).powi(2)).sum::<f64>();
+    let slope = if variance > 0.0 {
+        covariance / variance
+    } else {
+        0.0
+    };
+
+    (mean_y - slope * mean_x, slope)
+}
+
+/// Thresholds for the quality control report. Each figure of merit passes if
+/// it does not exceed its threshold.
+#[derive(Clone, Debug)]
+pub(crate) struct Thresholds {
+    /// Half width of the window around the reference chemical shift searched
+    /// for the reference peak, in ppm.
+    pub(crate) reference_window: f64,
+    /// Maximum full width at half maximum of the reference peak in Hz.
+    pub(crate) max_reference_fwhm: f64,
+    /// Maximum asymmetry of the reference peak, `|right - left| / (right +
+    /// left)` of the areas on either side of the maximum.
+    pub(crate) max_reference_asymmetry: f64,
+    /// Maximum baseline offset at the spectrum edges in noise standard
+    /// deviations.
+    pub(crate) max_baseline_offset: f64,
+    /// Maximum baseline drift across the spectrum in noise standard
+    /// deviations.
+    pub(crate) max_baseline_drift: f64,
+    /// Region of the water signal in ppm.
+    pub(crate) water_region: (f64, f64),
+    /// Maximum residual water intensity relative to the largest signal
+    /// outside of the water region.
+    pub(crate) max_water_residual: f64,
+    /// Maximum relative deviation of the chemical shift spacing from uniform.
+    pub(crate) max_spacing_deviation: f64,
+    /// Expected spectral width in ppm, if it should be checked.
+    pub(crate) expected_spectral_width: Option<f64>,
+    /// Maximum relative deviation from the expected spectral width.
+    pub(crate) spectral_width_tolerance: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            reference_window: 0.1,
+            max_reference_fwhm: 2.0,
+            max_reference_asymmetry: 0.1,
+            max_baseline_offset: 10.0,
+            max_baseline_drift: 10.0,
+            water_region: (4.7, 4.9),
+            max_water_residual: 0.5,
+            max_spacing_deviation: 1e-3,
+            expected_spectral_width: None,
+            spectral_width_tolerance: 0.01,
+        }
+    }
+}
+
+impl TryFrom<List> for Thresholds {
+    type Error = Error;
+
+    fn try_from(value: List) -> Result<Self> {
+        let mut thresholds = Self::default();
+        for (name, value) in value.iter() {
+            let number = || {
+                value
+                    .as_real()
+                    .ok_or_else(|| Error::from(format!("{} must be a numeric", name)))
+            };
+            match name {
+                "reference_window" => thresholds.reference_window = number()?,
+                "max_reference_fwhm" => thresholds.max_reference_fwhm = number()?,
+                "max_reference_asymmetry" => thresholds.max_reference_asymmetry = number()?,
+                "max_baseline_offset" => thresholds.max_baseline_offset = number()?,
+                "max_baseline_drift" => thresholds.max_baseline_drift = number()?,
+                "max_water_residual" => thresholds.max_water_residual = number()?,
+                "max_spacing_deviation" => thresholds.max_spacing_deviation = number()?,
+                "expected_spectral_width" => thresholds.expected_spectral_width = Some(number()?),
+                "spectral_width_tolerance" => thresholds.spectral_width_tolerance = number()?,
+                "water_region" => match value.as_real_vector() {
+                    Some(region) if region.len() == 2 => {
+                        thresholds.water_region = (region[0], region[1])
+                    }
+                    _ => return Err(Error::from("water_region must be a vector of length 2")),
+                },
+                _ => return Err(Error::from(format!("unknown threshold {:?}", name))),
+            }
+        }
+
+        Ok(thresholds)
+    }
+}
+
+/// Figures of merit of a spectrum together with the pass/fail flags.
+#[derive(Clone, Debug)]
+pub(crate) struct Report {
+    pub(crate) noise_sd: f64,
+    /// Shape of the reference peak, `None` if no peak was found.
+    pub(crate) reference_fwhm: Option<f64>,
+    pub(crate) reference_asymmetry: Option<f64>,
+    pub(crate) baseline_offset: f64,
+    pub(crate) baseline_slope: f64,
+    pub(crate) baseline_drift: f64,
+    pub(crate) water_residual: f64,
+    pub(crate) spacing_deviation: f64,
+    pub(crate) spectral_width: f64,
+    pub(crate) thresholds: Thresholds,
+}
+
+impl Report {
+    pub(crate) fn reference_fwhm_pass(&self) -> bool {
+        self.reference_fwhm
+            .is_some_and(|fwhm| fwhm <= self.thresholds.max_reference_fwhm)
+    }
+
+    pub(crate) fn reference_asymmetry_pass(&self) -> bool {
+        self.reference_asymmetry
+            .is_some_and(|asymmetry| asymmetry <= self.thresholds.max_reference_asymmetry)
+    }
+
+    pub(crate) fn baseline_offset_pass(&self) -> bool {
+        self.baseline_offset.abs() / self.noise_sd <= self.thresholds.max_baseline_offset
+    }
+
+    pub(crate) fn baseline_drift_pass(&self) -> bool {
+        self.baseline_drift <= self.thresholds.max_baseline_drift
+    }
+
+    pub(crate) fn water_residual_pass(&self) -> bool {
+        self.water_residual <= self.thresholds.max_water_residual
+    }
+
+    pub(crate) fn spectral_width_pass(&self) -> bool {
+        let spacing = self.spacing_deviation <= self.thresholds.max_spacing_deviation;
+        let width = self
+            .thresholds
+            .expected_spectral_width
+            .map(|expected| {
+                (self.spectral_width - expected).abs() / expected
+                    <= self.thresholds.spectral_width_tolerance
+            })
+            .unwrap_or(true);
+
+        spacing && width
+    }
+
+    pub(crate) fn passed(&self) -> bool {
+        self.reference_fwhm_pass()
+            && self.reference_asymmetry_pass()
+            && self.baseline_offset_pass()
+            && self.baseline_drift_pass()
+            && self.water_residual_pass()
+            && self.spectral_width_pass()
+    }
+
+    pub(crate) fn to_list(&self) -> Result<List> {
+        let names = [
+            "noise_sd",
+            "reference_fwhm_hz",
+            "reference_fwhm_pass",
+            "reference_asymmetry",
+            "reference_asymmetry_pass",
+            "baseline_offset",
+            "baseline_offset_pass",
+            "baseline_slope",
+            "baseline_drift",
+            "baseline_drift_pass",
+            "water_residual",
+            "water_residual_pass",
+            "spacing_deviation",
+            "spectral_width",
+            "spectral_width_pass",
+            "passed",
+        ];
+        let values: Vec<Robj> = vec![
+            self.noise_sd.into(),
+            vec![self.reference_fwhm].into(),
+            self.reference_fwhm_pass().into(),
+            vec![self.reference_asymmetry].into(),
+            self.reference_asymmetry_pass().into(),
+            self.baseline_offset.into(),
+            self.baseline_offset_pass().into(),
+            self.baseline_slope.into(),
+            self.baseline_drift.into(),
+            self.baseline_drift_pass().into(),
+            self.water_residual.into(),
+            self.water_residual_pass().into(),
+            self.spacing_deviation.into(),
+            self.spectral_width.into(),
+            self.spectral_width_pass().into(),
+            self.passed().into(),
+        ];
+
+        List::from_names_and_values(names, values)
+    }
+}
+
+/// Computes the quality control figures of merit of a spectrum.
+pub(crate) fn report(spectrum: &spectrum::Spectrum, thresholds: Thresholds) -> Result<Report> {
+    let chemical_shifts = spectrum.chemical_shifts();
+    let intensities = spectrum.intensities();
+    let len = chemical_shifts.len();
+    let noise = noise(spectrum, &[])?;
+
+    let reference = spectrum.reference_compound().chemical_shift();
+    let shape = reference_peak_shape(
+        chemical_shifts,
+        intensities,
+        reference,
+        thresholds.reference_window,
+        noise.mean,
+    );
+    let reference_fwhm = shape.map(|(fwhm, _)| fwhm * spectrum.frequency());
+    let reference_asymmetry = shape.map(|(_, asymmetry)| asymmetry);
+
+    let (first, last) = (chemical_shifts[0], chemical_shifts[len - 1]);
+    let (start, end) = spectrum.signal_boundaries();
+    let (signal_lower, signal_upper) = (start.min(end), start.max(end));
+    let (edge_x, edge_y): (Vec<f64>, Vec<f64>) = chemical_shifts
+        .iter()
+        .zip(intensities.iter())
+        .filter(|(x, _)| **x < signal_lower || **x > signal_upper)
+        .map(|(x, y)| (*x, *y))
+        .unzip();
+    let (intercept, baseline_slope) = if edge_x.len() >= 3 {
+        linear_fit(&edge_x, &edge_y)
+    } else {
+        (0.0, 0.0)
+    };
+    let baseline = |x: f64| intercept + baseline_slope * x;
+    // The offset is the larger deviation of the fitted baseline from zero at
+    // either end of the spectrum.
+    let baseline_offset = [baseline(first), baseline(last)]
+        .into_iter()
+        .max_by(|a, b| a.abs().total_cmp(&b.abs()))
+        .unwrap_or_default();
+    let spectral_width = (last - first).abs();
+    let baseline_drift = (baseline_slope * spectral_width).abs() / noise.sd;
+
+    let (water_lower, water_upper) = (
+        thresholds.water_region.0.min(thresholds.water_region.1),
+        thresholds.water_region.0.max(thresholds.water_region.1),
+    );
+    let (water, signal) = chemical_shifts.iter().zip(intensities.iter()).fold(
+        (0.0f64, 0.0f64),
+        |(water, signal), (x, y)| {
+            let y = (y - baseline(*x)).abs();
+            if *x >= water_lower && *x <= water_upper {
+                (water.max(y), signal)
+            } else if *x >= signal_lower && *x <= signal_upper {
+                (water, signal.max(y))
+            } else {
+                (water, signal)
+            }
+        },
+    );
+    let water_residual = if signal > 0.0 { water / signal } else { 0.0 };
+
+    let step = spectral_width / (len - 1) as f64;
+    let spacing_deviation = chemical_shifts
+        .windows(2)
+        .map(|pair| ((pair[1] - pair[0]).abs() - step).abs() / step)
+        .fold(0.0, f64::max);
+
+    Ok(Report {
+        noise_sd: noise.sd,
+        reference_fwhm,
+        reference_asymmetry,
+        baseline_offset,
+        baseline_slope,
+        baseline_drift,
+        water_residual,
+        spacing_deviation,
+        spectral_width,
+        thresholds,
+    })
+}
+
+/// Full width at half maximum (in ppm) and asymmetry of the largest peak
+/// within `window` ppm of `position`, or `None` if there is no peak above the
+/// baseline that falls below half height on both sides.
+fn reference_peak_shape(
+    chemical_shifts: &[f64],
+    intensities: &[f64],
+    position: f64,
+    window: f64,
+    baseline: f64,
+) -> Option<(f64, f64)> {
+    let apex = chemical_shifts
+        .iter()
+        .zip(intensities.iter())
+        .enumerate()
+        .filter(|(_, (x, _))| (**x - position).abs() <= window)
+        .max_by(|a, b| a.1 .1.total_cmp(b.1 .1))
+        .map(|(index, _)| index)?;
+    let height = intensities[apex] - baseline;
+    if height <= 0.0 {
+        return None;
+    }
+    let half = baseline + 0.5 * height;
+    let crossing = |indices: &mut dyn Iterator<Item = usize>| {
+        let mut previous = apex;
+        for index in indices {
+            if intensities[index] < half {
+                let t =
+                    (intensities[previous] - half) / (intensities[previous] - intensities[index]);
+                return Some(
+                    chemical_shifts[previous]
+                        + t * (chemical_shifts[index] - chemical_shifts[previous]),
+                );
+            }
+            previous = index;
+        }
+        None
+    };
+    let left = crossing(&mut (0..apex).rev())?;
+    let right = crossing(&mut (apex + 1..chemical_shifts.len()))?;
+    let fwhm = (right - left).abs();
+    let area = |indices: &mut dyn Iterator<Item = usize>| {
+        indices
+            .take_while(|index| {
+                (chemical_shifts[*index] - chemical_shifts[apex]).abs() <= 2.0 * fwhm
+            })
+            .map(|index| intensities[index] - baseline)
+            .sum::<f64>()
+    };
+    let lower = area(&mut (0..apex).rev());
+    let upper = area(&mut (apex + 1..chemical_shifts.len()));
+    let asymmetry = if lower + upper != 0.0 {
+        ((upper - lower) / (upper + lower)).abs()
+    } else {
+        0.0
+    };
+
+    Some((fwhm, asymmetry))
+}
@@ -0,0 +1,73 @@
+use extendr_api::prelude::*;
+use metabodecon::deconvolution::Lorentzian;
+use serde::{Deserialize, Serialize};
+
+/// Concentration and number of protons of the reference compound, used as
+/// internal standard for absolute quantification.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Standard {
+    /// Concentration in the unit the analyte concentrations are wanted in.
+    pub(crate) concentration: f64,
+    /// Number of protons contributing to the reference signal.
+    pub(crate) protons: f64,
+}
+
+impl Standard {
+    pub(crate) fn new(concentration: f64, protons: f64) -> Result<Self> {
+        if !(concentration.is_finite() && concentration > 0.0) {
+            return Err(Error::from("concentration must be a positive number"));
+        }
+        if !(protons.is_finite() && protons > 0.0) {
+            return Err(Error::from("protons must be a positive number"));
+        }
+
+        Ok(Self {
+            concentration,
+            protons,
+        })
+    }
+
+    /// Absolute concentrations of analytes from their signal areas and proton
+    /// counts, as `concentration * (area / protons) / (reference_area /
+    /// reference_protons)`. A single proton count applies to all areas.
+    pub(crate) fn concentrations(
+        &self,
+        reference_area: f64,
+        areas: &[f64],
+        protons: &[f64],
+    ) -> Result<Vec<f64>> {
+        if !(reference_area.is_finite() && reference_area > 0.0) {
+            return Err(Error::from(
+                "the area of the reference compound must be positive",
+            ));
+        }
+        if protons.len() != 1 && protons.len() != areas.len() {
+            return Err(Error::from(
+                "protons must have length 1 or the same length as areas",
+            ));
+        }
+        if protons.iter().any(|protons| protons.is_nan() || *protons <= 0.0) {
+            return Err(Error::from("protons must be positive"));
+        }
+        let per_proton = reference_area / self.protons;
+
+        Ok(areas
+            .iter()
+            .enumerate()
+            .map(|(i, area)| {
+                let protons = protons[i.min(protons.len() - 1)];
+                self.concentration * area / protons / per_proton
+            })
+            .collect())
+    }
+}
+
+/// Summed area of the Lorentzians whose maximum lies within `window` ppm of
+/// the chemical shift of the reference compound.
+pub(crate) fn reference_area(lorentzians: &[Lorentzian], chemical_shift: f64, window: f64) -> f64 {
+    lorentzians
+        .iter()
+        .filter(|lorentzian| (lorentzian.maxp() - chemical_shift).abs() <= window)
+        .map(|lorentzian| std::f64::consts::PI * lorentzian.sf())
+        .sum()
+}
@@ -0,0 +1,126 @@
+use extendr_api::prelude::*;
+
+/// Number of samples on either side of the target used by the windowed sinc
+/// interpolation.
+const SINC_HALF_WIDTH: usize = 8;
+
+/// Interpolation methods available for resampling.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Interpolation {
+    Linear,
+    CubicSpline,
+    Sinc,
+}
+
+impl std::str::FromStr for Interpolation {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "linear" => Ok(Self::Linear),
+            "cubic" | "spline" | "cubic_spline" => Ok(Self::CubicSpline),
+            "sinc" => Ok(Self::Sinc),
+            _ => Err(Error::from(format!(
+                "unknown interpolation method {:?}, expected \"linear\", \"cubic\" or \"sinc\"",
+                value
+            ))),
+        }
+    }
+}
+
+/// Interpolates the intensities `y` sampled at `x` onto `targets`. Targets
+/// outside the range of `x` are set to zero. `x` may be increasing or
+/// decreasing.
+pub(crate) fn resample(x: &[f64], y: &[f64], targets: &[f64], method: Interpolation) -> Vec<f64> {
+    if x.len() < 2 {
+        return vec![0.0; targets.len()];
+    }
+    let (x, y) = if x[0] > x[x.len() - 1] {
+        (
+            x.iter().rev().copied().collect::<Vec<f64>>(),
+            y.iter().rev().copied().collect::<Vec<f64>>(),
+        )
+    } else {
+        (x.to_vec(), y.to_vec())
+    };
+    let second_derivatives = match method {
+        Interpolation::CubicSpline => spline_second_derivatives(&x, &y),
+        _ => Vec::new(),
+    };
+
+    targets
+        .iter()
+        .map(|target| {
+            if *target < x[0] || *target > x[x.len() - 1] {
+                return 0.0;
+            }
+            let upper = x.partition_point(|x| x < target).clamp(1, x.len() - 1);
+            let lower = upper - 1;
+            match method {
+                Interpolation::Linear => {
+                    let t = (target - x[lower]) / (x[upper] - x[lower]);
+                    y[lower] + t * (y[upper] - y[lower])
+                }
+                Interpolation::CubicSpline => {
+                    let h = x[upper] - x[lower];
+                    let a = (x[upper] - target) / h;
+                    let b = (target - x[lower]) / h;
+                    a * y[lower]
+                        + b * y[upper]
+                        + ((a.powi(3) - a) * second_derivatives[lower]
+                            + (b.powi(3) - b) * second_derivatives[upper])
+                            * h.powi(2)
+                            / 6.0
+                }
+                Interpolation::Sinc => windowed_sinc(&x, &y, *target, lower),
+            }
+        })
+        .collect()
+}
+
+/// Second derivatives of the natural cubic spline through `(x, y)`.
+fn spline_second_derivatives(x: &[f64], y: &[f64]) -> Vec<f64> {
+    let n = x.len();
+    let mut second_derivatives = vec![0.0; n];
+    let mut u = vec![0.0; n];
+    for i in 1..n - 1 {
+        let sigma = (x[i] - x[i - 1]) / (x[i + 1] - x[i - 1]);
+        let p = sigma * second_derivatives[i - 1] + 2.0;
+        second_derivatives[i] = (sigma - 1.0) / p;
+        let slope = (y[i + 1] - y[i]) / (x[i + 1] - x[i]) - (y[i] - y[i - 1]) / (x[i] - x[i - 1]);
+        u[i] = (6.0 * slope / (x[i + 1] - x[i - 1]) - sigma * u[i - 1]) / p;
+    }
+    second_derivatives[n - 1] = 0.0;
+    for i in (0..n - 1).rev() {
+        second_derivatives[i] = second_derivatives[i] * second_derivatives[i + 1] + u[i];
+    }
+
+    second_derivatives
+}
+
+/// Lanczos windowed sinc interpolation, assuming uniformly spaced `x`.
+fn windowed_sinc(x: &[f64], y: &[f64], target: f64, lower: usize) -> f64 {
+    let step = (x[x.len() - 1] - x[0]) / (x.len() - 1) as f64;
+    let window = SINC_HALF_WIDTH as f64;
+    let first = lower.saturating_sub(SINC_HALF_WIDTH - 1);
+    let last = (lower + SINC_HALF_WIDTH).min(x.len() - 1);
+    let sinc = |t: f64| {
+        if t.abs() < 1e-12 {
+            1.0
+        } else {
+            let t = std::f64::consts::PI * t;
+            t.sin() / t
+        }
+    };
+
+    (first..=last)
+        .map(|k| {
+            let t = (target - x[k]) / step;
+            if t.abs() >= window {
+                0.0
+            } else {
+                y[k] * sinc(t) * sinc(t / window)
+            }
+        })
+        .sum()
+}
@@ -1,10 +1,36 @@
+use crate::annotations;
+use crate::binning;
+use crate::boundaries;
+use crate::deconvolution;
+use crate::library;
+use crate::parameters;
+use crate::quality;
+use crate::quantification;
+use crate::resampling;
+use crate::util;
 use extendr_api::prelude::*;
 use metabodecon::spectrum;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct Spectrum {
+    #[serde(flatten)]
     inner: spectrum::Spectrum,
+    #[serde(default)]
+    signal_regions: Option<Vec<(f64, f64)>>,
+    #[serde(default)]
+    ignore_regions: Option<Vec<(f64, f64)>>,
+    #[serde(default)]
+    reference_standard: Option<quantification::Standard>,
+    #[serde(default)]
+    acquisition: Option<parameters::Acquisition>,
+    #[serde(default)]
+    metadata: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    annotations: annotations::Annotations,
+    #[serde(default)]
+    source: Option<String>,
 }
 
 impl AsRef<spectrum::Spectrum> for Spectrum {
@@ -15,7 +41,16 @@ impl AsRef<spectrum::Spectrum> for Spectrum {
 
 impl From<spectrum::Spectrum> for Spectrum {
     fn from(value: spectrum::Spectrum) -> Self {
-        Self { inner: value }
+        Self {
+            inner: value,
+            signal_regions: None,
+            ignore_regions: None,
+            reference_standard: None,
+            acquisition: None,
+            metadata: None,
+            annotations: annotations::Annotations::new(),
+            source: None,
+        }
     }
 }
 
@@ -38,6 +73,63 @@ impl TryFrom<&Robj> for Spectrum {
     }
 }
 
+/// Signal boundaries as passed from R, either a numeric vector of length 2 or
+/// `NULL`/`"auto"` to detect them from the data.
+#[derive(Copy, Clone, Debug)]
+enum SignalBoundaries {
+    Fixed((f64, f64)),
+    Auto,
+}
+
+impl TryFrom<&Robj> for SignalBoundaries {
+    type Error = Error;
+
+    fn try_from(value: &Robj) -> Result<Self> {
+        if value.is_null() || value.as_str() == Some("auto") {
+            return Ok(Self::Auto);
+        }
+        match value.as_real_vector() {
+            Some(bounds) if bounds.len() == 2 => Ok(Self::Fixed((bounds[0], bounds[1]))),
+            _ => Err(Error::from(
+                "signal_boundaries must be a vector of length 2, NULL or \"auto\"",
+            )),
+        }
+    }
+}
+
+impl SignalBoundaries {
+    /// Boundaries to read a spectrum with before the actual boundaries are
+    /// detected. They only need to lie within the chemical shift range.
+    fn placeholder(self, range: impl FnOnce() -> Result<(f64, f64)>) -> (f64, f64) {
+        match self {
+            Self::Fixed(signal_boundaries) => signal_boundaries,
+            Self::Auto => match range() {
+                Ok((lower, upper)) => {
+                    let width = upper - lower;
+                    (lower + 0.25 * width, upper - 0.25 * width)
+                }
+                Err(error) => throw_r_error(format!(
+                    "{}, pass signal_boundaries explicitly instead",
+                    error
+                )),
+            },
+        }
+    }
+
+    fn apply(self, spectrum: &mut spectrum::Spectrum) {
+        if let Self::Auto = self {
+            let detected =
+                match boundaries::detect(spectrum.chemical_shifts(), spectrum.intensities()) {
+                    Ok(detected) => detected,
+                    Err(error) => throw_r_error(error.to_string()),
+                };
+            if let Err(error) = spectrum.set_signal_boundaries(detected) {
+                throw_r_error(error.to_string());
+            }
+        }
+    }
+}
+
 impl Spectrum {
     pub(crate) fn recover_list(spectra: &List) -> Result<Vec<Spectrum>> {
         spectra
@@ -46,6 +138,299 @@ impl Spectrum {
             .map(|r_obj| r_obj.try_into())
             .collect::<Result<Vec<Spectrum>>>()
     }
+
+    /// Signal regions, if the spectrum has been split into several disjoint
+    /// regions.
+    pub(crate) fn disjoint_signal_regions(&self) -> Option<&[(f64, f64)]> {
+        self.signal_regions.as_deref()
+    }
+
+    /// Marks the data points within the signal regions, or the signal
+    /// boundaries if there are none, and outside the given ignore regions.
+    pub(crate) fn signal_mask(&self, ignore_regions: &[(f64, f64)]) -> Vec<bool> {
+        let boundaries = [self.inner.signal_boundaries()];
+        let signal_regions = self.disjoint_signal_regions().unwrap_or(&boundaries);
+        let contains = |regions: &[(f64, f64)], x: f64| {
+            regions
+                .iter()
+                .any(|(start, end)| x >= start.min(*end) && x <= start.max(*end))
+        };
+
+        self.inner
+            .chemical_shifts()
+            .iter()
+            .map(|x| contains(signal_regions, *x) && !contains(ignore_regions, *x))
+            .collect()
+    }
+
+    /// Ignore regions specific to this spectrum. They are used in addition to
+    /// the ignore regions of the deconvoluter.
+    pub(crate) fn sample_ignore_regions(&self) -> Option<&[(f64, f64)]> {
+        self.ignore_regions.as_deref()
+    }
+
+    /// User annotations of the spectrum, carried over to its deconvolutions.
+    pub(crate) fn sample_annotations(&self) -> &annotations::Annotations {
+        &self.annotations
+    }
+
+    /// File or directory the spectrum was read from, if known.
+    pub(crate) fn source_path(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// Fields added to `metabodecon::spectrum::Spectrum`.
+    const FIELDS: [&'static str; 7] = [
+        "signal_regions",
+        "ignore_regions",
+        "reference_standard",
+        "acquisition",
+        "metadata",
+        "annotations",
+        "source",
+    ];
+
+    /// Creates a new spectrum from the given data, carrying over the metadata
+    /// of this spectrum. The index of the reference compound is updated to the
+    /// data point closest to its chemical shift.
+    pub(crate) fn with_data(
+        &self,
+        chemical_shifts: Vec<f64>,
+        intensities: Vec<f64>,
+        signal_boundaries: (f64, f64),
+    ) -> Result<Spectrum> {
+        let reference = self.inner.reference_compound();
+        let index = chemical_shifts
+            .iter()
+            .enumerate()
+            .min_by(|a, b| {
+                (a.1 - reference.chemical_shift())
+                    .abs()
+                    .total_cmp(&(b.1 - reference.chemical_shift()).abs())
+            })
+            .map(|(index, _)| index)
+            .unwrap_or(0);
+        let reference = spectrum::meta::ReferenceCompound::new(
+            reference.chemical_shift(),
+            index,
+            reference.name().map(|name| name.to_string()),
+            reference.method(),
+        );
+        let mut spectrum = spectrum::Spectrum::new(chemical_shifts, intensities, signal_boundaries)
+            .map_err(|error| Error::from(error.to_string()))?;
+        spectrum.set_nucleus(self.inner.nucleus().to_string().as_str());
+        spectrum.set_frequency(self.inner.frequency());
+        spectrum.set_reference_compound(reference);
+        let mut spectrum = Spectrum::from(spectrum);
+        if let Some(regions) = &self.signal_regions {
+            let (start, end) = spectrum.inner.signal_boundaries();
+            let (lower, upper) = (start.min(end), start.max(end));
+            let regions = regions
+                .iter()
+                .map(|(start, end)| (start.max(lower), end.min(upper)))
+                .filter(|(start, end)| start < end)
+                .collect::<Vec<(f64, f64)>>();
+            if !regions.is_empty() {
+                spectrum.signal_regions = Some(regions);
+            }
+        }
+        spectrum.ignore_regions = self.ignore_regions.clone();
+        spectrum.reference_standard = self.reference_standard;
+        spectrum.acquisition = self.acquisition.clone();
+        spectrum.metadata = self.metadata.clone();
+        spectrum.annotations = self.annotations.clone();
+        spectrum.source = self.source.clone();
+
+        Ok(spectrum)
+    }
+
+    /// Restricts the spectrum to `[lower, upper]` and sets the intensities
+    /// outside of the given regions to zero. The signal boundaries are shrunk
+    /// to the kept range and to exclude zeroed regions at their edges.
+    fn subset(&self, lower: f64, upper: f64, regions: &[(f64, f64)], keep: bool) -> Spectrum {
+        let is_zeroed = |x: f64| {
+            let inside = regions.iter().any(|(start, end)| x >= *start && x <= *end);
+            inside != keep
+        };
+        let (chemical_shifts, intensities): (Vec<f64>, Vec<f64>) = self
+            .inner
+            .chemical_shifts()
+            .iter()
+            .zip(self.inner.intensities().iter())
+            .filter(|(x, _)| **x >= lower && **x <= upper)
+            .map(|(x, y)| (*x, if is_zeroed(*x) { 0.0 } else { *y }))
+            .unzip();
+        if chemical_shifts.len() < 3 {
+            throw_r_error("the selected range contains too few data points");
+        }
+        let (first, last) = (
+            chemical_shifts[0],
+            chemical_shifts[chemical_shifts.len() - 1],
+        );
+        let (lower, upper) = (first.min(last), first.max(last));
+        let (start, end) = self.inner.signal_boundaries();
+        let mut signal_start = start.min(end).clamp(lower, upper);
+        let mut signal_end = start.max(end).clamp(lower, upper);
+        let zeroed = if keep {
+            let mut zeroed = Vec::with_capacity(regions.len() + 1);
+            let mut previous = lower;
+            regions.iter().for_each(|(start, end)| {
+                zeroed.push((previous, *start));
+                previous = *end;
+            });
+            zeroed.push((previous, upper));
+            zeroed
+        } else {
+            regions.to_vec()
+        };
+        zeroed.iter().for_each(|(zeroed_start, zeroed_end)| {
+            if *zeroed_start <= signal_start && *zeroed_end > signal_start {
+                signal_start = *zeroed_end;
+            }
+        });
+        zeroed.iter().rev().for_each(|(zeroed_start, zeroed_end)| {
+            if *zeroed_end >= signal_end && *zeroed_start < signal_end {
+                signal_end = *zeroed_start;
+            }
+        });
+        if signal_start >= signal_end {
+            throw_r_error("no signal region is left within the selected range");
+        }
+        let signal_boundaries = if start <= end {
+            (signal_start, signal_end)
+        } else {
+            (signal_end, signal_start)
+        };
+
+        match self.with_data(chemical_shifts, intensities, signal_boundaries) {
+            Ok(spectrum) => spectrum,
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    /// Returns the intensities of `other` on the chemical shift axis of this
+    /// spectrum, interpolating them if a resampling method is given.
+    fn aligned_intensities(&self, other: &Spectrum, resample: &Nullable<String>) -> Vec<f64> {
+        let chemical_shifts = self.inner.chemical_shifts();
+        if util::shares_axis(chemical_shifts, other.inner.chemical_shifts()) {
+            return other.inner.intensities().to_vec();
+        }
+        match resample {
+            NotNull(method) => {
+                let method = match method.parse::<resampling::Interpolation>() {
+                    Ok(method) => method,
+                    Err(error) => throw_r_error(error.to_string()),
+                };
+                resampling::resample(
+                    other.inner.chemical_shifts(),
+                    other.inner.intensities(),
+                    chemical_shifts,
+                    method,
+                )
+            }
+            Null => throw_r_error(
+                "spectra have different chemical shift axes, pass a resampling method to interpolate them onto a common axis",
+            ),
+        }
+    }
+
+    fn combine(
+        spectra: List,
+        resample: Nullable<String>,
+        statistic: fn(&mut [f64]) -> f64,
+    ) -> Spectrum {
+        let spectra = match Spectrum::recover_list(&spectra) {
+            Ok(spectra) => spectra,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let first = match spectra.first() {
+            Some(first) => first,
+            None => throw_r_error("at least one spectrum is required"),
+        };
+        let aligned = spectra
+            .iter()
+            .map(|spectrum| first.aligned_intensities(spectrum, &resample))
+            .collect::<Vec<Vec<f64>>>();
+        let mut values = vec![0.0; spectra.len()];
+        let intensities = (0..first.inner.intensities().len())
+            .map(|i| {
+                values
+                    .iter_mut()
+                    .zip(aligned.iter())
+                    .for_each(|(value, intensities)| *value = intensities[i]);
+                statistic(&mut values)
+            })
+            .collect::<Vec<f64>>();
+
+        first.with_intensities(intensities)
+    }
+
+    fn with_intensities(&self, intensities: Vec<f64>) -> Spectrum {
+        match self.with_data(
+            self.inner.chemical_shifts().to_vec(),
+            intensities,
+            self.inner.signal_boundaries(),
+        ) {
+            Ok(spectrum) => spectrum,
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    fn resample_to(&self, chemical_shifts: &[f64], method: &str) -> Spectrum {
+        let method = match method.parse::<resampling::Interpolation>() {
+            Ok(method) => method,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        if chemical_shifts.len() < 2 {
+            throw_r_error("chemical_shifts must contain at least two values");
+        }
+        let intensities = resampling::resample(
+            self.inner.chemical_shifts(),
+            self.inner.intensities(),
+            chemical_shifts,
+            method,
+        );
+        let (lower, upper) = chemical_shifts
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |acc, x| {
+                (acc.0.min(*x), acc.1.max(*x))
+            });
+        let (start, end) = self.inner.signal_boundaries();
+        let signal_boundaries = (start.clamp(lower, upper), end.clamp(lower, upper));
+
+        match self.with_data(chemical_shifts.to_vec(), intensities, signal_boundaries) {
+            Ok(spectrum) => spectrum,
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    fn bin<S: AsRef<spectrum::Spectrum>>(
+        spectra: &[S],
+        binning: &binning::Binning,
+        ignore_regions: Nullable<List>,
+    ) -> List {
+        let ignore_regions = match util::optional_regions(ignore_regions) {
+            Ok(ignore_regions) => ignore_regions,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let buckets = match binning::bin_spectra(spectra, binning, &ignore_regions) {
+            Ok(buckets) => buckets,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+
+        match buckets.to_list() {
+            Ok(buckets) => buckets,
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    fn bin_table_from_bounds(start: Vec<f64>, end: Vec<f64>) -> Vec<(f64, f64)> {
+        if start.len() != end.len() {
+            throw_r_error("start and end must have the same length");
+        }
+
+        start.into_iter().zip(end).collect()
+    }
 }
 
 /// @eval make_r_docs("Spectrum")
@@ -54,12 +439,19 @@ impl Spectrum {
     pub(crate) fn new(
         chemical_shifts: Vec<f64>,
         intensities: Vec<f64>,
-        signal_boundaries: Vec<f64>,
+        signal_boundaries: Robj,
     ) -> Self {
-        if signal_boundaries.len() != 2 {
-            throw_r_error("signal_boundaries must be a vector of length 2");
-        }
-        let signal_boundaries = (signal_boundaries[0], signal_boundaries[1]);
+        let signal_boundaries = match SignalBoundaries::try_from(&signal_boundaries) {
+            Ok(signal_boundaries) => signal_boundaries,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let signal_boundaries = match signal_boundaries {
+            SignalBoundaries::Fixed(signal_boundaries) => signal_boundaries,
+            SignalBoundaries::Auto => match boundaries::detect(&chemical_shifts, &intensities) {
+                Ok(detected) => detected,
+                Err(error) => throw_r_error(error.to_string()),
+            },
+        };
 
         match spectrum::Spectrum::new(chemical_shifts, intensities, signal_boundaries) {
             Ok(spectrum) => spectrum.into(),
@@ -101,22 +493,103 @@ impl Spectrum {
         result.insert("index", index.into());
         result.insert("name", Nullable::from(name).into());
         result.insert("method", Nullable::from(method).into());
+        let standard = self.reference_standard;
+        let concentration = standard.map(|standard| standard.concentration);
+        let protons = standard.map(|standard| standard.protons);
+        result.insert("concentration", Nullable::from(concentration).into());
+        result.insert("protons", Nullable::from(protons).into());
 
         List::from_hashmap(result)
     }
 
-    pub(crate) fn set_signal_boundaries(&mut self, signal_boundaries: Vec<f64>) {
-        if signal_boundaries.len() != 2 {
-            throw_r_error("signal_boundaries must be a vector of length 2");
+    pub(crate) fn set_signal_boundaries(&mut self, signal_boundaries: Robj) {
+        let signal_boundaries = match SignalBoundaries::try_from(&signal_boundaries) {
+            Ok(signal_boundaries) => signal_boundaries,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        match signal_boundaries {
+            SignalBoundaries::Fixed(signal_boundaries) => {
+                match self.inner.set_signal_boundaries(signal_boundaries) {
+                    Ok(_) => (),
+                    Err(error) => throw_r_error(error.to_string()),
+                }
+            }
+            SignalBoundaries::Auto => SignalBoundaries::Auto.apply(&mut self.inner),
+        }
+        self.signal_regions = None;
+    }
+
+    pub(crate) fn signal_regions(&self) -> List {
+        match &self.signal_regions {
+            Some(regions) => util::regions_to_list(regions),
+            None => util::regions_to_list(&[self.inner.signal_boundaries()]),
+        }
+    }
+
+    pub(crate) fn set_signal_regions(&mut self, regions: List) {
+        let regions = match util::regions_from_list(&regions) {
+            Ok(regions) => util::merge_regions(&regions),
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        if regions.is_empty() {
+            throw_r_error("at least one signal region is required");
+        }
+        let signal_boundaries = (regions[0].0, regions[regions.len() - 1].1);
+        if let Err(error) = self.inner.set_signal_boundaries(signal_boundaries) {
+            throw_r_error(error.to_string());
         }
-        let signal_boundaries = (signal_boundaries[0], signal_boundaries[1]);
+        self.signal_regions = match regions.len() {
+            1 => None,
+            _ => Some(regions),
+        };
+    }
 
-        match self.inner.set_signal_boundaries(signal_boundaries) {
-            Ok(_) => (),
+    pub(crate) fn detect_signal_boundaries(&self) -> Vec<f64> {
+        match boundaries::detect(self.inner.chemical_shifts(), self.inner.intensities()) {
+            Ok((lower, upper)) => vec![lower, upper],
             Err(error) => throw_r_error(error.to_string()),
         }
     }
 
+    pub(crate) fn ignore_regions(&self) -> Nullable<List> {
+        match &self.ignore_regions {
+            Some(regions) => NotNull(util::regions_to_list(regions)),
+            None => Null,
+        }
+    }
+
+    pub(crate) fn add_ignore_region(&mut self, start: f64, end: f64) {
+        if !(start.is_finite() && end.is_finite()) || start == end {
+            throw_r_error("ignore region must have two distinct finite bounds");
+        }
+        self.ignore_regions
+            .get_or_insert_with(Vec::new)
+            .push((start.min(end), start.max(end)));
+    }
+
+    pub(crate) fn clear_ignore_regions(&mut self) {
+        self.ignore_regions = None;
+    }
+
+    pub(crate) fn annotations(&self) -> Result<List> {
+        annotations::to_list(&self.annotations)
+    }
+
+    pub(crate) fn set_annotations(&mut self, annotations: List) {
+        match annotations::from_list(&annotations) {
+            Ok(annotations) => self.annotations.extend(annotations),
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    pub(crate) fn remove_annotation(&mut self, name: &str) {
+        self.annotations.remove(name);
+    }
+
+    pub(crate) fn clear_annotations(&mut self) {
+        self.annotations.clear();
+    }
+
     pub(crate) fn set_nucleus(&mut self, nucleus: &str) {
         self.inner.set_nucleus(nucleus);
     }
@@ -143,6 +616,33 @@ impl Spectrum {
         let referencing_method = reference
             .get("referencing_method")
             .and_then(|method| std::str::FromStr::from_str(method.as_str().unwrap()).ok());
+        let number = |value: &Robj| {
+            value
+                .as_real()
+                .or_else(|| value.as_integer().map(f64::from))
+        };
+        let concentration = reference
+            .get("concentration")
+            .filter(|concentration| !concentration.is_null());
+        let protons = reference
+            .get("protons")
+            .filter(|protons| !protons.is_null());
+        let standard = match (concentration, protons) {
+            (Some(concentration), Some(protons)) => {
+                let standard = number(concentration)
+                    .zip(number(protons))
+                    .ok_or_else(|| Error::from("concentration and protons must be numeric"))
+                    .and_then(|(concentration, protons)| {
+                        quantification::Standard::new(concentration, protons)
+                    });
+                match standard {
+                    Ok(standard) => Some(standard),
+                    Err(error) => throw_r_error(error.to_string()),
+                }
+            }
+            (None, None) => None,
+            _ => throw_r_error("concentration and protons must be given together"),
+        };
         let reference = spectrum::meta::ReferenceCompound::new(
             chemical_shift,
             index as usize,
@@ -150,61 +650,466 @@ impl Spectrum {
             referencing_method,
         );
         self.inner.set_reference_compound(reference);
+        self.reference_standard = standard;
+    }
+
+    pub(crate) fn metadata(&self) -> Result<Nullable<List>> {
+        match &self.metadata {
+            Some(metadata) => {
+                let (names, values): (Vec<&str>, Vec<Robj>) = metadata
+                    .iter()
+                    .map(|(key, value)| {
+                        let value = match parameters::parse_decimal(value) {
+                            Some(number) => number.into(),
+                            None => value.into(),
+                        };
+                        (key.as_str(), value)
+                    })
+                    .unzip();
+                Ok(NotNull(List::from_names_and_values(names, values)?))
+            }
+            None => Ok(Null),
+        }
+    }
+
+    pub(crate) fn acquisition(&self) -> Result<Nullable<List>> {
+        match &self.acquisition {
+            Some(acquisition) => Ok(NotNull(acquisition.to_list()?)),
+            None => Ok(Null),
+        }
+    }
+
+    pub(crate) fn scale_acquisition(&self, basis: Nullable<List>) -> Self {
+        let basis = match basis {
+            NotNull(basis) => parameters::Acquisition::try_from(basis),
+            Null => Ok(parameters::Acquisition::default()),
+        };
+        let scaled = match &self.acquisition {
+            Some(acquisition) => basis.and_then(|basis| {
+                acquisition
+                    .scaling(&basis)
+                    .map(|factor| (factor, acquisition.scaled_to(&basis)))
+            }),
+            None => Err(Error::from("the spectrum has no acquisition parameters")),
+        };
+        match scaled {
+            Ok((factor, acquisition)) => {
+                let mut spectrum = self.scale(factor);
+                spectrum.acquisition = Some(acquisition);
+
+                spectrum
+            }
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    pub(crate) fn set_reference_concentration(&mut self, concentration: f64, protons: f64) {
+        match quantification::Standard::new(concentration, protons) {
+            Ok(standard) => self.reference_standard = Some(standard),
+            Err(error) => throw_r_error(error.to_string()),
+        }
+    }
+
+    pub(crate) fn reference_area(
+        &self,
+        deconvolution: &deconvolution::Deconvolution,
+        window: f64,
+    ) -> f64 {
+        quantification::reference_area(
+            deconvolution.as_ref().lorentzians(),
+            self.inner.reference_compound().chemical_shift(),
+            window,
+        )
+    }
+
+    pub(crate) fn quantify(
+        &self,
+        areas: Vec<f64>,
+        protons: Vec<f64>,
+        reference_area: f64,
+    ) -> Result<Vec<f64>> {
+        let standard = self.reference_standard.ok_or_else(|| {
+            Error::from("the concentration of the reference compound has not been set")
+        })?;
+
+        standard.concentrations(reference_area, &areas, &protons)
     }
 
     pub(crate) fn read_bruker(
         path: &str,
         experiment: u32,
         processing: u32,
-        signal_boundaries: Vec<f64>,
+        signal_boundaries: Robj,
     ) -> Self {
-        if signal_boundaries.len() != 2 {
-            throw_r_error("signal_boundaries must be a vector of length 2");
-        }
-        let signal_boundaries = (signal_boundaries[0], signal_boundaries[1]);
-
-        match spectrum::Bruker::read_spectrum(path, experiment, processing, signal_boundaries) {
-            Ok(spectrum) => spectrum.into(),
+        let mode = match SignalBoundaries::try_from(&signal_boundaries) {
+            Ok(mode) => mode,
             Err(error) => throw_r_error(error.to_string()),
-        }
+        };
+        let signal_boundaries =
+            mode.placeholder(|| parameters::bruker_range(path, experiment, processing));
+        let mut spectrum = match spectrum::Bruker::read_spectrum(
+            path,
+            experiment,
+            processing,
+            signal_boundaries,
+        ) {
+            Ok(spectrum) => spectrum,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        mode.apply(&mut spectrum);
+        let mut spectrum = Spectrum::from(spectrum);
+        spectrum.acquisition = parameters::bruker_acquisition(path, experiment, processing).ok();
+        spectrum.metadata = parameters::bruker_metadata(path, experiment, processing).ok();
+        spectrum.source = Some(
+            parameters::bruker_processing_dir(path, experiment, processing)
+                .display()
+                .to_string(),
+        );
+
+        spectrum
     }
 
     pub(crate) fn read_bruker_set(
         path: &str,
         experiment: u32,
         processing: u32,
-        signal_boundaries: Vec<f64>,
+        signal_boundaries: Robj,
     ) -> List {
-        if signal_boundaries.len() != 2 {
-            throw_r_error("signal_boundaries must be a vector of length 2");
-        }
-        let signal_boundaries = (signal_boundaries[0], signal_boundaries[1]);
-        let spectra =
-            match spectrum::Bruker::read_spectra(path, experiment, processing, signal_boundaries) {
-                Ok(spectra) => spectra
-                    .into_iter()
-                    .map(|spectrum| spectrum.into())
-                    .collect::<Vec<Spectrum>>(),
-                Err(error) => throw_r_error(error.to_string()),
-            };
+        let mode = match SignalBoundaries::try_from(&signal_boundaries) {
+            Ok(mode) => mode,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let signal_boundaries = mode.placeholder(|| {
+            parameters::bruker_samples(path, experiment)?
+                .iter()
+                .map(|sample| parameters::bruker_range(sample, experiment, processing))
+                .try_fold((f64::NEG_INFINITY, f64::INFINITY), |acc, range| {
+                    range.map(|(lower, upper)| (acc.0.max(lower), acc.1.min(upper)))
+                })
+                .and_then(|(lower, upper)| match lower < upper {
+                    true => Ok((lower, upper)),
+                    false => Err(Error::from("the spectra share no chemical shift range")),
+                })
+        });
+        let samples = match parameters::bruker_samples(path, experiment) {
+            Ok(samples) => samples,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let spectra = samples
+            .iter()
+            .map(|sample| {
+                let mut spectrum = match spectrum::Bruker::read_spectrum(
+                    sample,
+                    experiment,
+                    processing,
+                    signal_boundaries,
+                ) {
+                    Ok(spectrum) => spectrum,
+                    Err(error) => throw_r_error(error.to_string()),
+                };
+                mode.apply(&mut spectrum);
+                let mut spectrum = Spectrum::from(spectrum);
+                spectrum.acquisition =
+                    parameters::bruker_acquisition(sample, experiment, processing).ok();
+                spectrum.metadata =
+                    parameters::bruker_metadata(sample, experiment, processing).ok();
+                spectrum.source = Some(
+                    parameters::bruker_processing_dir(sample, experiment, processing)
+                        .display()
+                        .to_string(),
+                );
+
+                spectrum
+            })
+            .collect::<Vec<Spectrum>>();
 
         List::from_values(spectra)
     }
 
-    pub(crate) fn read_jcampdx(path: &str, signal_boundaries: Vec<f64>) -> Self {
-        if signal_boundaries.len() != 2 {
-            throw_r_error("signal_boundaries must be a vector of length 2");
+    pub(crate) fn read_jcampdx(path: &str, signal_boundaries: Robj) -> Self {
+        let mode = match SignalBoundaries::try_from(&signal_boundaries) {
+            Ok(mode) => mode,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let signal_boundaries = mode.placeholder(|| parameters::jcampdx_range(path));
+        let mut spectrum = match spectrum::JcampDx::read_spectrum(path, signal_boundaries) {
+            Ok(spectrum) => spectrum,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        mode.apply(&mut spectrum);
+        let mut spectrum = Spectrum::from(spectrum);
+        spectrum.metadata = parameters::jcampdx_metadata(path).ok();
+        spectrum.source = Some(path.to_string());
+
+        spectrum
+    }
+
+    pub(crate) fn bin_uniform(&self, width: f64, ignore_regions: Nullable<List>) -> List {
+        Self::bin(
+            &[self],
+            &binning::Binning::Uniform { width },
+            ignore_regions,
+        )
+    }
+
+    pub(crate) fn bin_uniform_set(
+        spectra: List,
+        width: f64,
+        ignore_regions: Nullable<List>,
+    ) -> List {
+        let spectra = match Spectrum::recover_list(&spectra) {
+            Ok(spectra) => spectra,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+
+        Self::bin(
+            &spectra,
+            &binning::Binning::Uniform { width },
+            ignore_regions,
+        )
+    }
+
+    pub(crate) fn bin_table(
+        &self,
+        start: Vec<f64>,
+        end: Vec<f64>,
+        ignore_regions: Nullable<List>,
+    ) -> List {
+        let bins = Self::bin_table_from_bounds(start, end);
+
+        Self::bin(&[self], &binning::Binning::Table { bins }, ignore_regions)
+    }
+
+    pub(crate) fn bin_table_set(
+        spectra: List,
+        start: Vec<f64>,
+        end: Vec<f64>,
+        ignore_regions: Nullable<List>,
+    ) -> List {
+        let spectra = match Spectrum::recover_list(&spectra) {
+            Ok(spectra) => spectra,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let bins = Self::bin_table_from_bounds(start, end);
+
+        Self::bin(&spectra, &binning::Binning::Table { bins }, ignore_regions)
+    }
+
+    pub(crate) fn bin_adaptive(
+        &self,
+        width: f64,
+        slackness: f64,
+        ignore_regions: Nullable<List>,
+    ) -> List {
+        let binning = binning::Binning::Adaptive { width, slackness };
+
+        Self::bin(&[self], &binning, ignore_regions)
+    }
+
+    pub(crate) fn bin_adaptive_set(
+        spectra: List,
+        width: f64,
+        slackness: f64,
+        ignore_regions: Nullable<List>,
+    ) -> List {
+        let spectra = match Spectrum::recover_list(&spectra) {
+            Ok(spectra) => spectra,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let binning = binning::Binning::Adaptive { width, slackness };
+
+        Self::bin(&spectra, &binning, ignore_regions)
+    }
+
+    pub(crate) fn crop(&self, start: f64, end: f64) -> Self {
+        self.subset(start.min(end), start.max(end), &[], false)
+    }
+
+    pub(crate) fn keep_regions(&self, regions: List) -> Self {
+        let regions = match util::regions_from_list(&regions) {
+            Ok(regions) => util::merge_regions(&regions),
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        if regions.is_empty() {
+            throw_r_error("at least one region is required");
         }
-        let signal_boundaries = (signal_boundaries[0], signal_boundaries[1]);
+        let lower = regions[0].0;
+        let upper = regions[regions.len() - 1].1;
 
-        match spectrum::JcampDx::read_spectrum(path, signal_boundaries) {
-            Ok(spectrum) => spectrum.into(),
+        self.subset(lower, upper, &regions, true)
+    }
+
+    pub(crate) fn remove_regions(&self, regions: List) -> Self {
+        let regions = match util::regions_from_list(&regions) {
+            Ok(regions) => util::merge_regions(&regions),
             Err(error) => throw_r_error(error.to_string()),
+        };
+
+        self.subset(f64::NEG_INFINITY, f64::INFINITY, &regions, false)
+    }
+
+    pub(crate) fn add(&self, other: &Spectrum, resample: Nullable<String>) -> Self {
+        let other = self.aligned_intensities(other, &resample);
+        let intensities = self
+            .inner
+            .intensities()
+            .iter()
+            .zip(other.iter())
+            .map(|(a, b)| a + b)
+            .collect();
+
+        self.with_intensities(intensities)
+    }
+
+    pub(crate) fn subtract(&self, other: &Spectrum, resample: Nullable<String>) -> Self {
+        let other = self.aligned_intensities(other, &resample);
+        let intensities = self
+            .inner
+            .intensities()
+            .iter()
+            .zip(other.iter())
+            .map(|(a, b)| a - b)
+            .collect();
+
+        self.with_intensities(intensities)
+    }
+
+    pub(crate) fn scale(&self, factor: f64) -> Self {
+        let intensities = self
+            .inner
+            .intensities()
+            .iter()
+            .map(|intensity| intensity * factor)
+            .collect();
+
+        self.with_intensities(intensities)
+    }
+
+    pub(crate) fn mean(spectra: List, resample: Nullable<String>) -> Self {
+        Self::combine(spectra, resample, |values| {
+            values.iter().sum::<f64>() / values.len() as f64
+        })
+    }
+
+    pub(crate) fn median(spectra: List, resample: Nullable<String>) -> Self {
+        Self::combine(spectra, resample, |values| {
+            util::median(values).unwrap_or(f64::NAN)
+        })
+    }
+
+    pub(crate) fn noise_level(&self, regions: Nullable<List>) -> Result<f64> {
+        let regions = util::optional_regions(regions)?;
+
+        Ok(quality::noise(self.as_ref(), &regions)?.sd)
+    }
+
+    pub(crate) fn snr(&self, start: f64, end: f64, noise_regions: Nullable<List>) -> Result<f64> {
+        let noise_regions = util::optional_regions(noise_regions)?;
+        let noise = quality::noise(self.as_ref(), &noise_regions)?;
+        let (_, snr) = quality::snr(self.as_ref(), start, end, noise)?;
+
+        Ok(snr)
+    }
+
+    pub(crate) fn snr_set(
+        spectra: List,
+        start: f64,
+        end: f64,
+        noise_regions: Nullable<List>,
+    ) -> Result<Robj> {
+        let spectra = Spectrum::recover_list(&spectra)?;
+        let noise_regions = util::optional_regions(noise_regions)?;
+        let len = spectra.len();
+        let mut noise_mean = Vec::<f64>::with_capacity(len);
+        let mut noise_sd = Vec::<f64>::with_capacity(len);
+        let mut signal = Vec::<f64>::with_capacity(len);
+        let mut snr = Vec::<f64>::with_capacity(len);
+        for spectrum in spectra.iter() {
+            let noise = quality::noise(spectrum.as_ref(), &noise_regions)?;
+            let (height, ratio) = quality::snr(spectrum.as_ref(), start, end, noise)?;
+            noise_mean.push(noise.mean);
+            noise_sd.push(noise.sd);
+            signal.push(height);
+            snr.push(ratio);
         }
+        let columns: Vec<(&str, Robj)> = vec![
+            ("spectrum", (1..=len as i32).collect::<Vec<i32>>().into()),
+            ("noise_mean", noise_mean.into()),
+            ("noise_sd", noise_sd.into()),
+            ("signal", signal.into()),
+            ("snr", snr.into()),
+        ];
+
+        util::data_frame(columns, len)
+    }
+
+    pub(crate) fn quality_report(&self, thresholds: Nullable<List>) -> Result<List> {
+        let thresholds = match thresholds {
+            NotNull(thresholds) => quality::Thresholds::try_from(thresholds)?,
+            Null => quality::Thresholds::default(),
+        };
+
+        quality::report(self.as_ref(), thresholds)?.to_list()
+    }
+
+    pub(crate) fn fit_library(
+        &self,
+        library: &str,
+        reference: Nullable<String>,
+        settings: Nullable<List>,
+    ) -> Result<List> {
+        let settings = match settings {
+            NotNull(settings) => library::FitSettings::try_from(settings)?,
+            Null => library::FitSettings::default(),
+        };
+        let metabolites = library::read(library)?;
+        let find = |name: &str| {
+            metabolites
+                .iter()
+                .position(|metabolite| metabolite.name.eq_ignore_ascii_case(name))
+        };
+        let reference = match reference {
+            NotNull(name) => Some(find(&name).ok_or_else(|| {
+                Error::from(format!("reference {:?} is not in the library", name))
+            })?),
+            Null => self.inner.reference_compound().name().and_then(find),
+        };
+        let mask = self.signal_mask(self.sample_ignore_regions().unwrap_or_default());
+
+        library::fit(
+            &metabolites,
+            reference,
+            self.inner.chemical_shifts(),
+            self.inner.intensities(),
+            &mask,
+            self.inner.frequency(),
+            &settings,
+        )
+    }
+
+    pub(crate) fn resample(&self, chemical_shifts: Vec<f64>, method: &str) -> Self {
+        self.resample_to(&chemical_shifts, method)
+    }
+
+    pub(crate) fn resample_like(&self, reference: &Spectrum, method: &str) -> Self {
+        self.resample_to(reference.inner.chemical_shifts(), method)
+    }
+
+    pub(crate) fn resample_set(spectra: List, chemical_shifts: Vec<f64>, method: &str) -> List {
+        let spectra = match Spectrum::recover_list(&spectra) {
+            Ok(spectra) => spectra,
+            Err(error) => throw_r_error(error.to_string()),
+        };
+        let spectra = spectra
+            .iter()
+            .map(|spectrum| spectrum.resample_to(&chemical_shifts, method))
+            .collect::<Vec<Spectrum>>();
+
+        List::from_values(spectra)
     }
 
     pub(crate) fn write_json(&self, path: &str) {
-        let serialized = match serde_json::to_string_pretty(self.as_ref()) {
+        let serialized = match serde_json::to_string_pretty(self) {
             Ok(serialized) => serialized,
             Err(error) => throw_r_error(error.to_string()),
         };
@@ -214,14 +1119,23 @@ impl Spectrum {
     pub(crate) fn read_json(path: &str) -> Self {
         let serialized = std::fs::read_to_string(path).unwrap();
 
-        match serde_json::from_str::<spectrum::Spectrum>(&serialized) {
-            Ok(deserialized) => deserialized.into(),
-            Err(error) => throw_r_error(error.to_string()),
-        }
+        let spectrum = util::deserialize_with(
+            || serde_json::from_str::<Spectrum>(&serialized),
+            || serde_json::from_str::<spectrum::Spectrum>(&serialized),
+            || serde_json::from_str(&serialized),
+            &Self::FIELDS,
+        );
+        let mut spectrum = match spectrum {
+            Ok(spectrum) => spectrum,
+            Err(error) => throw_r_error(error),
+        };
+        spectrum.source.get_or_insert_with(|| path.to_string());
+
+        spectrum
     }
 
     pub(crate) fn write_bin(&self, path: &str) {
-        let serialized = match rmp_serde::to_vec(self.as_ref()) {
+        let serialized = match rmp_serde::to_vec(self) {
             Ok(serialized) => serialized,
             Err(error) => throw_r_error(error.to_string()),
         };
@@ -231,10 +1145,19 @@ impl Spectrum {
     pub(crate) fn read_bin(path: &str) -> Self {
         let serialized = std::fs::read(path).unwrap();
 
-        match rmp_serde::from_slice::<spectrum::Spectrum>(&serialized) {
-            Ok(deserialized) => deserialized.into(),
-            Err(error) => throw_r_error(error.to_string()),
-        }
+        let spectrum = util::deserialize_with(
+            || rmp_serde::from_slice::<Spectrum>(&serialized),
+            || rmp_serde::from_slice::<spectrum::Spectrum>(&serialized),
+            || rmp_serde::from_slice(&serialized),
+            &Self::FIELDS,
+        );
+        let mut spectrum = match spectrum {
+            Ok(spectrum) => spectrum,
+            Err(error) => throw_r_error(error),
+        };
+        spectrum.source.get_or_insert_with(|| path.to_string());
+
+        spectrum
     }
 }
 
@@ -0,0 +1,209 @@
+use crate::fitting;
+use extendr_api::prelude::*;
+use metabodecon::deconvolution::Lorentzian;
+use serde::{Deserialize, Serialize};
+
+/// Width in ppm of the window fitted around a target if none is given.
+const DEFAULT_WINDOW: f64 = 0.05;
+
+/// Signal to fit at a known position.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Target {
+    /// Initial position in ppm.
+    pub(crate) position: f64,
+    /// Initial full width at half maximum in ppm, estimated from the data if
+    /// not given.
+    pub(crate) width: Option<f64>,
+    /// Range the position may move within, in ppm.
+    pub(crate) bounds: Option<(f64, f64)>,
+    /// Width in ppm of the window centred on the position whose data points
+    /// are fitted.
+    pub(crate) window: f64,
+}
+
+/// Reads the targets from a list or data frame with a `position` column and
+/// the optional columns `width`, `lower`, `upper` and `window`. Missing
+/// (`NA`) values in the optional columns fall back to the defaults.
+pub(crate) fn targets_from_list(targets: &List) -> Result<Vec<Target>> {
+    let columns = targets.clone().into_hashmap();
+    let column = |name: &str| -> Result<Option<Vec<f64>>> {
+        match columns.get(name) {
+            Some(values) => values
+                .as_real_vector()
+                .map(Some)
+                .ok_or_else(|| Error::from(format!("{} must be numeric", name))),
+            None => Ok(None),
+        }
+    };
+    let position =
+        column("position")?.ok_or_else(|| Error::from("targets must have a position column"))?;
+    let optional = |name: &str| -> Result<Vec<Option<f64>>> {
+        match column(name)? {
+            Some(values) if values.len() == position.len() => Ok(values
+                .into_iter()
+                .map(|value| Some(value).filter(|value| value.is_finite()))
+                .collect()),
+            Some(_) => Err(Error::from(format!(
+                "{} must have the same length as position",
+                name
+            ))),
+            None => Ok(vec![None; position.len()]),
+        }
+    };
+    let width = optional("width")?;
+    let lower = optional("lower")?;
+    let upper = optional("upper")?;
+    let window = optional("window")?;
+
+    position
+        .iter()
+        .enumerate()
+        .map(|(i, position)| {
+            if !position.is_finite() {
+                return Err(Error::from(format!("position {} is missing", i + 1)));
+            }
+            if width[i].is_some_and(|width| width <= 0.0)
+                || window[i].is_some_and(|window| window <= 0.0)
+            {
+                return Err(Error::from(format!(
+                    "width and window of target {} must be positive",
+                    i + 1
+                )));
+            }
+            let bounds = match (lower[i], upper[i]) {
+                (None, None) => None,
+                (lower, upper) => {
+                    let lower = lower.unwrap_or(f64::NEG_INFINITY);
+                    let upper = upper.unwrap_or(f64::INFINITY);
+                    if !(lower <= *position && *position <= upper) {
+                        return Err(Error::from(format!(
+                            "position of target {} is outside its bounds",
+                            i + 1
+                        )));
+                    }
+                    Some((lower, upper))
+                }
+            };
+            Ok(Target {
+                position: *position,
+                width: width[i],
+                bounds,
+                window: window[i].unwrap_or(DEFAULT_WINDOW),
+            })
+        })
+        .collect()
+}
+
+/// Table of targets in the form read by [`targets_from_list`], with `NA` for
+/// the values that were not given.
+pub(crate) fn table(targets: &[Target]) -> Result<Robj> {
+    let bound = |select: fn((f64, f64)) -> f64| {
+        targets
+            .iter()
+            .map(|target| target.bounds.map(select).filter(|bound| bound.is_finite()))
+            .collect::<Vec<Option<f64>>>()
+    };
+    let columns: Vec<(&str, Robj)> = vec![
+        (
+            "position",
+            targets
+                .iter()
+                .map(|target| target.position)
+                .collect::<Vec<f64>>()
+                .into(),
+        ),
+        (
+            "width",
+            targets
+                .iter()
+                .map(|target| target.width)
+                .collect::<Vec<Option<f64>>>()
+                .into(),
+        ),
+        ("lower", bound(|bounds| bounds.0).into()),
+        ("upper", bound(|bounds| bounds.1).into()),
+        (
+            "window",
+            targets
+                .iter()
+                .map(|target| target.window)
+                .collect::<Vec<f64>>()
+                .into(),
+        ),
+    ];
+
+    crate::util::data_frame(columns, targets.len())
+}
+
+/// Initial Lorentzian for a target. The height is the intensity closest to
+/// the position and, unless given, the half width is the distance at which
+/// the intensity first falls below half of the height on either side.
+pub(crate) fn initial_guess(target: &Target, x: &[f64], y: &[f64]) -> Lorentzian {
+    let spacing = match x.len() {
+        0 | 1 => 1.0,
+        len => (x[len - 1] - x[0]).abs() / (len - 1) as f64,
+    };
+    let nearest = x
+        .iter()
+        .enumerate()
+        .min_by(|a, b| {
+            (a.1 - target.position)
+                .abs()
+                .total_cmp(&(b.1 - target.position).abs())
+        })
+        .map(|(index, _)| index)
+        .unwrap_or(0);
+    let height = y.get(nearest).copied().unwrap_or(0.0).max(f64::EPSILON);
+    let half_width = match target.width {
+        Some(width) => 0.5 * width,
+        None => {
+            let limit = 0.5 * target.window;
+            let within = |i: &usize| (x[*i] - target.position).abs() <= limit;
+            let below = |i: &usize| y[*i] < 0.5 * height;
+            let left = (0..nearest)
+                .rev()
+                .take_while(within)
+                .find(below)
+                .map(|i| (x[i] - x[nearest]).abs());
+            let right = (nearest + 1..x.len())
+                .take_while(within)
+                .find(below)
+                .map(|i| (x[i] - x[nearest]).abs());
+            match (left, right) {
+                (Some(left), Some(right)) => 0.5 * (left + right),
+                (Some(distance), None) | (None, Some(distance)) => distance,
+                (None, None) => 2.0 * spacing,
+            }
+        }
+    }
+    .max(spacing);
+
+    Lorentzian::new(
+        height * half_width.powi(2),
+        half_width.powi(2),
+        target.position,
+    )
+}
+
+/// Constraint keeping a target within its bounds, or within its window if it
+/// has none.
+pub(crate) fn constraint(target: &Target) -> fitting::Constraint {
+    let half_window = 0.5 * target.window;
+    let window = (target.position - half_window, target.position + half_window);
+
+    fitting::Constraint {
+        position: Some(target.bounds.unwrap_or(window)),
+        ..Default::default()
+    }
+}
+
+/// Marks the data points within the window of any target.
+pub(crate) fn window_mask(targets: &[Target], x: &[f64]) -> Vec<bool> {
+    x.iter()
+        .map(|x| {
+            targets
+                .iter()
+                .any(|target| (x - target.position).abs() <= 0.5 * target.window)
+        })
+        .collect()
+}
@@ -0,0 +1,150 @@
+use extendr_api::prelude::*;
+use serde::de::IgnoredAny;
+use std::collections::{BTreeMap, HashMap};
+
+/// Assembles named columns of equal length into an R `data.frame`, keeping
+/// the column order as given.
+pub(crate) fn data_frame(columns: Vec<(&str, Robj)>, rows: usize) -> Result<Robj> {
+    let (names, values): (Vec<&str>, Vec<Robj>) = columns.into_iter().unzip();
+    let mut frame: Robj = List::from_names_and_values(names, values)?.into();
+    frame.set_attrib("row.names", (1..=rows as i32).collect::<Vec<i32>>())?;
+    frame.set_class(["data.frame"])?;
+
+    Ok(frame)
+}
+
+/// Builds a numeric R matrix from rows of equal length.
+pub(crate) fn matrix(rows: &[Vec<f64>], columns: usize) -> Result<Robj> {
+    let mut values = Vec::<f64>::with_capacity(rows.len() * columns);
+    for column in 0..columns {
+        rows.iter().for_each(|row| values.push(row[column]));
+    }
+    let mut matrix: Robj = values.into();
+    matrix.set_attrib("dim", vec![rows.len() as i32, columns as i32])?;
+
+    Ok(matrix)
+}
+
+/// Reads regions given either as numeric vectors `c(start, end)` or as lists
+/// with `start` and `end` elements, as returned by
+/// `Deconvoluter$ignore_regions()`.
+pub(crate) fn regions_from_list(regions: &List) -> Result<Vec<(f64, f64)>> {
+    regions
+        .values()
+        .map(|region| {
+            if let Some(bounds) = region.as_real_vector() {
+                if bounds.len() == 2 {
+                    return Ok((bounds[0], bounds[1]));
+                }
+            } else if let Some(bounds) = region.as_list() {
+                let bounds = bounds.into_hashmap();
+                let start = bounds.get("start").and_then(|start| start.as_real());
+                let end = bounds.get("end").and_then(|end| end.as_real());
+                if let (Some(start), Some(end)) = (start, end) {
+                    return Ok((start, end));
+                }
+            }
+            Err(Error::from(
+                "regions must be given as c(start, end) or list(start = , end = )",
+            ))
+        })
+        .collect()
+}
+
+/// Converts regions into a list of `list(start = , end = )` elements.
+pub(crate) fn regions_to_list(regions: &[(f64, f64)]) -> List {
+    let regions: Vec<Robj> = regions
+        .iter()
+        .map(|(start, end)| {
+            let mut result = HashMap::<&str, Robj>::new();
+            result.insert("start", start.into());
+            result.insert("end", end.into());
+
+            List::from_hashmap(result).into()
+        })
+        .collect();
+
+    List::from_values(regions)
+}
+
+/// Like [`regions_from_list`], but treats `NULL` as no regions.
+pub(crate) fn optional_regions(regions: Nullable<List>) -> Result<Vec<(f64, f64)>> {
+    match regions {
+        NotNull(regions) => regions_from_list(&regions),
+        Null => Ok(Vec::new()),
+    }
+}
+
+/// Orders each region as `(start, end)` with `start < end`, sorts them and
+/// merges overlapping regions.
+pub(crate) fn merge_regions(regions: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    let mut regions = regions
+        .iter()
+        .map(|(a, b)| (a.min(*b), a.max(*b)))
+        .collect::<Vec<_>>();
+    regions.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Vec<(f64, f64)> = Vec::with_capacity(regions.len());
+    for (start, end) in regions {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
+
+/// Checks whether two chemical shift axes coincide up to a small fraction of
+/// their spacing.
+pub(crate) fn shares_axis(a: &[f64], b: &[f64]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let tolerance = match a.len() {
+        0 | 1 => 0.0,
+        len => 1e-6 * (a[len - 1] - a[0]).abs() / (len - 1) as f64,
+    };
+
+    a.iter()
+        .zip(b.iter())
+        .all(|(x, y)| (x - y).abs() <= tolerance)
+}
+
+/// Median of the given values, or `None` if there are none. The values are
+/// sorted in place, so callers can pass a reused scratch buffer.
+pub(crate) fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let middle = values.len() / 2;
+    if values.len() % 2 == 0 {
+        Some(0.5 * (values[middle - 1] + values[middle]))
+    } else {
+        Some(values[middle])
+    }
+}
+
+/// Deserializes a wrapper type, falling back to the format of the wrapped
+/// `metabodecon` type for files written by earlier versions. `read_fields`
+/// reads the top-level fields of the file. The fallback is only taken if the
+/// file has none of the `fields` added by the wrapper, so that their content
+/// is never silently dropped.
+pub(crate) fn deserialize_with<T, U: Into<T>, E: std::fmt::Display>(
+    read: impl Fn() -> std::result::Result<T, E>,
+    read_inner: impl Fn() -> std::result::Result<U, E>,
+    read_fields: impl Fn() -> std::result::Result<BTreeMap<String, IgnoredAny>, E>,
+    fields: &[&str],
+) -> std::result::Result<T, String> {
+    let error = match read() {
+        Ok(value) => return Ok(value),
+        Err(error) => error.to_string(),
+    };
+    let wrapped =
+        read_fields().is_ok_and(|present| fields.iter().any(|field| present.contains_key(*field)));
+    if wrapped {
+        return Err(error);
+    }
+
+    read_inner().map(Into::into).map_err(|_| error)
+}